@@ -3,6 +3,67 @@
 (define broker-port 1883)
 (define broker-user "user")
 (define broker-pass "password")
+;; To keep the password out of this file, it can be read from a file instead, or from a systemd
+;; credential called broker-pass (see LoadCredential=). The same works for nrepl-token.
+;; (define broker-pass-file "/etc/heinzelmann/broker-pass")
 (define program-location "examples/hs100.scm")
+;; Topic filters to subscribe to once the program has run, optionally with a QoS (default 0).
+;; (define subscriptions '("zigbee2mqtt/#" ("tasmota/+/stat/POWER" 1)))
+;; Connection settings, shown with their defaults. With client-id-suffix, a random suffix is
+;; appended to client-id, so several instances can share this config.
+;; (define keep-alive 5)
+;; (define clean-session #t)
+;; (define inflight 100)
+;; (define client-id-suffix #f)
+;; One of off, error, warn, info, debug or trace, optionally followed by levels for single
+;; targets like "nrepl", "config" or "program" (the program's log-info and friends).
+;; --log-level takes precedence.
+;; (define log-level "info,program=debug")
+;; One of text, json or journald. Defaults to journald when running under systemd.
+;; (define log-format "text")
+;; Where files that should survive a restart are kept. Programs can find it as data-dir.
+;; (define data-dir "/var/lib/heinzelmann")
 (define local-repl #t)
+;; Lines entered in the local REPL are kept here. Defaults to ~/.heinzelmann_history.
+;; (define repl-history-file "/var/lib/heinzelmann/history")
 (define nrepl (list "127.0.0.1"))
+;; Serves Prometheus metrics at http://metrics-addr:metrics-port/metrics, and a health report
+;; at /healthz that answers 503 while the broker is disconnected or the VM is stuck.
+;; (define metrics-enable #f)
+;; (define metrics-addr "127.0.0.1")
+;; (define metrics-port 9188)
+;; Messages are counted by the first of these filters matching their topic, then by the
+;; subscription they arrived through.
+;; (define metrics-topic-filters (list "zigbee2mqtt/#" "tasmota/#"))
+;; Publishes heinzelmann's status, version, program hash, hooks, timers and handler errors under
+;; <prefix>/heinzelmann/, and accepts "reload" on <prefix>/heinzelmann/command.
+;; (define status-topic-prefix "home")
+;; Records every incoming message, the hook it matched, what was published and timer firings,
+;; with a correlation id linking each effect to its cause. Rotated at audit-log-max-size bytes.
+;; (define audit-log "/var/lib/heinzelmann/audit.log")
+;; (define audit-log-max-size 10000000)
+;; (define audit-log-keep 5)
+;; Appends every message the program handles to this file, for `heinzelmann replay`. --record takes precedence.
+;; (define record-file "/var/lib/heinzelmann/recording")
+;; Handles messages from the broker as usual, but only logs what would have been published.
+;; Status topics are turned off as well. Also enabled by --dry-run.
+;; (define dry-run #f)
+;; Evaluations that run longer than this (in milliseconds) are aborted. Set to #f to disable.
+(define handler-timeout 10000)
+;; (define handler-instruction-limit 1000000)
+;; (define repl-timeout 60000)
+;; (define repl-instruction-limit 1000000)
+;; The nREPL server can be moved off its default address and port, or turned off entirely.
+;; (define nrepl-enable #t)
+;; (define nrepl-addr "127.0.0.1")
+;; (define nrepl-port 7888)
+;; Writes the port the nREPL server listens on to this file, so editors can connect on their own.
+;; (define nrepl-port-file ".nrepl-port")
+;; nREPL clients have to send this token along with their first message. Like broker-pass, it
+;; can also be read from a file or a systemd credential.
+;; (define nrepl-token "secret")
+;; (define nrepl-token-file "/etc/heinzelmann/nrepl-token")
+;; A Unix socket speaking the nREPL protocol, for `heinzelmann eval` and `heinzelmann repl`.
+;; Anyone who can write to it can run code, so its mode (an octal string) defaults to "600".
+;; (define control-socket "/run/heinzelmann/control.sock")
+;; (define control-socket-mode "660")
@@ -13,20 +13,18 @@
 ;; If you want to use this, replace the uuid bits. No guarantee it will work with yours though!
 ;; For docs on this stuff, see https://github.com/arandall/meross/blob/main/doc/protocol.md
 
-(register-event! "/appliance/2103224640798990846748e1e9662393/publish" 
-   (lambda x 
-     (let* ((payload (string->jsexpr (cadr x)))
-            (header (hash-get payload 'header)))
-       (cond ((string=? (hash-get header 'namespace) "Appliance.Control.Bind")
+(register-json-event! "/appliance/2103224640798990846748e1e9662393/publish" 
+   (lambda (_ payload)
+     (let* ((header (json-ref payload 'header)))
+       (cond ((string=? (json-ref header 'namespace) "Appliance.Control.Bind")
               ; Automatically ACK a new Meross plug, otherwise it will boot loop (or something similar)
               ; This isn't required for all models, but I only have one that needs this
               (send-simple "/appliance/2103224640798990846748e1e9662393/subscribe"
                            (value->jsexpr-string (hash-insert payload 'header (hash-insert header 'method "SETACK"))))
               (displayln "Meross Appliance.Control.Bind received and ACKed on /appliance/2103224640798990846748e1e9662393/publish!"))
-             ((and (string=? (hash-get header 'namespace) "Appliance.Control.ToggleX")
-                   (string=? (hash-get header 'method) "PUSH"))
-              (send-meross-status "custom/meross" (hash-get (hash-get payload 'payload)
-                                                            'togglex)))))))
+             ((and (string=? (json-ref header 'namespace) "Appliance.Control.ToggleX")
+                   (string=? (json-ref header 'method) "PUSH"))
+              (send-meross-status "custom/meross" (json-ref payload 'payload 'togglex)))))))
 
 ;; Sends a more readable Meross status message, iterating over all of the plug's channels
 (define (send-meross-status base-topic channels)
@@ -0,0 +1,135 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// An append-only log of what happened and why: incoming messages, the hooks they matched, timer
+// firings, evaluations and everything published. Entries caused by the same message, timer or
+// evaluation share a correlation id, so a published message can be traced back to its cause.
+//
+// Entries are JSON objects, one per line. The file is rotated to <path>.1, <path>.2, ... once it
+// grows past its maximum size.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use chrono::Local;
+use steel::SteelVal;
+use crate::utils;
+
+pub type SharedAudit = Arc<Mutex<Audit>>;
+
+#[derive(Clone, Debug)]
+pub struct AuditConfiguration {
+    path: String,
+    max_size: u64,
+    keep: usize,
+}
+
+impl AuditConfiguration {
+    pub fn new(path: String, max_size: u64, keep: usize) -> AuditConfiguration {
+        return AuditConfiguration { path, max_size, keep };
+    }
+}
+
+struct AuditFile {
+    config: AuditConfiguration,
+    file: File,
+    size: u64,
+}
+
+impl AuditFile {
+    fn open(config: AuditConfiguration) -> io::Result<AuditFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        return Ok(AuditFile { config, file, size });
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                let _ = fs::rename(format!("{}.{}", path, n), format!("{}.{}", path, n + 1));
+            }
+            fs::rename(path, format!("{}.1", path))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        return Ok(());
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        return Ok(());
+    }
+}
+
+pub struct Audit {
+    file: Option<AuditFile>,
+    // The correlation id of whatever the VM is running right now.
+    current: Option<String>,
+}
+
+impl Audit {
+    // Without a configuration, or if the file can't be opened, nothing is recorded.
+    pub fn shared(config: Option<AuditConfiguration>) -> SharedAudit {
+        let file = config.and_then(|config| {
+            let path = config.path.clone();
+            return AuditFile::open(config)
+                .map_err(|e| log::error!("Unable to open the audit log at {}: {}", path, e))
+                .ok();
+        });
+        return Arc::new(Mutex::new(Audit { file, current: None }));
+    }
+
+    pub fn new_correlation() -> String {
+        return utils::random_uuid();
+    }
+
+    pub fn set_current(&mut self, correlation: Option<String>) {
+        self.current = correlation;
+    }
+
+    pub fn record(&mut self, correlation: Option<&str>, event: &str, fields: &[(&str, &str)]) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let mut line = format!("{{\"time\":{},\"correlation\":{},\"event\":{}",
+            utils::json_string(&Local::now().to_rfc3339()),
+            correlation.map_or("null".into(), utils::json_string),
+            utils::json_string(event));
+        for (name, value) in fields {
+            line.push_str(&format!(",{}:{}", utils::json_string(name), utils::json_string(value)));
+        }
+        line.push_str("}\n");
+        if let Err(e) = file.write(&line) {
+            log::error!("Unable to write to the audit log at {}: {}", file.config.path, e);
+        }
+    }
+
+    // Records an entry caused by whatever the VM is running.
+    pub fn record_current(&mut self, event: &str, fields: &[(&str, &str)]) {
+        let current = self.current.clone();
+        self.record(current.as_deref(), event, fields);
+    }
+}
+
+// (audit-hook kind topic hook), with kind being "event" or "timer" and hook the key of the
+// matched hook or #f.
+pub fn audit_hook_closure(audit: SharedAudit) -> impl Fn(String, String, SteelVal) -> () {
+    return move |kind, topic, hook| {
+        let hook = match hook {
+            SteelVal::StringV(s) => s.to_string(),
+            _ => "none".into(),
+        };
+        audit.lock().unwrap().record_current("hook", &[("kind", &kind), ("topic", &topic), ("hook", &hook)]);
+    };
+}
@@ -0,0 +1,246 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::str::FromStr;
+use crate::{ctl, logging, PROGRAM_NAME};
+
+pub fn usage() -> String {
+    return format!("Usage: {0} [run] [OPTIONS] [CONFIG]
+       {0} check [OPTIONS] [CONFIG]
+       {0} replay [OPTIONS] RECORDING [CONFIG]
+       {0} eval [--socket PATH] CODE
+       {0} repl [--socket PATH]
+
+Commands:
+  run                   connect to the broker and run the program (default)
+  check                 load the config and run the program without connecting, then exit
+  replay                feed a recording through the program on a simulated clock and print
+                        what it would have published, without connecting
+  eval                  evaluate CODE in a running instance through its control socket
+  repl                  open a REPL on a running instance through its control socket
+
+Options:
+  --broker HOST[:PORT]  connect to this broker instead of the configured one
+  --program PATH        run this program instead of the configured one
+  --no-repl             don't start the local REPL
+  --record PATH         append incoming messages to PATH, for replay
+  --dry-run             handle messages from the broker as usual, but only log what would
+                        have been published
+  --speed FACTOR        replay this many times faster than recorded, or as fast as possible
+                        with 0 (the default)
+  --log-level LEVELS    off, error, warn, info, debug or trace, optionally followed by
+                        levels for single targets, like info,nrepl=debug
+  --socket PATH         the control socket to connect to (default: {1})
+  --version             print the version and exit
+  -h, --help            print this help and exit
+
+CONFIG defaults to /etc/{0}/config.scm.", PROGRAM_NAME, ctl::DEFAULT_SOCKET);
+}
+
+// Settings given on the command line, which take precedence over the config file.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub config_location: String,
+    pub broker: Option<(String, Option<u16>)>,
+    pub program: Option<String>,
+    pub no_repl: bool,
+    pub record: Option<String>,
+    pub dry_run: bool,
+    pub log_filter: Option<logging::Filter>,
+}
+
+impl Options {
+    fn new() -> Options {
+        return Options {
+            config_location: format!("/etc/{}/config.scm", PROGRAM_NAME),
+            broker: None,
+            program: None,
+            no_repl: false,
+            record: None,
+            dry_run: false,
+            log_filter: None,
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Run(Options),
+    Check(Options),
+    Replay { options: Options, recording: String, speed: f64 },
+    Eval { socket: String, code: String },
+    Repl { socket: String },
+    Version,
+    Help,
+}
+
+// The value of a flag, given either as "--flag=value" or as the next argument.
+fn flag_value<'a, I: Iterator<Item = &'a String>>(flag: &str, inline: Option<&str>, arguments: &mut I) -> Result<String, String> {
+    if let Some(value) = inline {
+        return Ok(value.into());
+    }
+    return arguments.next()
+        .cloned()
+        .ok_or(format!("{} needs a value.", flag));
+}
+
+fn parse_broker(value: &str) -> Result<(String, Option<u16>), String> {
+    match value.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse().map_err(|_| format!("'{}' is not a valid broker port.", port))?;
+            return Ok((host.into(), Some(port)));
+        },
+        None => return Ok((value.into(), None)),
+    }
+}
+
+// Parses the arguments after the program name.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let mut arguments = args.iter().peekable();
+    let subcommand = match arguments.peek().map(|a| a.as_str()) {
+        Some("run") | Some("check") | Some("replay") | Some("eval") | Some("repl") => arguments.next().unwrap().as_str(),
+        // A config path on its own, as in older versions, still runs it.
+        _ => "run",
+    };
+
+    let mut options = Options::new();
+    let mut socket = ctl::DEFAULT_SOCKET.to_string();
+    let mut speed = 0.0;
+    let mut positional: Vec<String> = vec![];
+    while let Some(argument) = arguments.next() {
+        let (flag, inline) = match argument.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+            _ => (argument.as_str(), None),
+        };
+        match flag {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--version" => return Ok(Command::Version),
+            "--broker" => options.broker = Some(parse_broker(&flag_value(flag, inline, &mut arguments)?)?),
+            "--program" => options.program = Some(flag_value(flag, inline, &mut arguments)?),
+            "--no-repl" => options.no_repl = true,
+            "--dry-run" => options.dry_run = true,
+            "--record" => options.record = Some(flag_value(flag, inline, &mut arguments)?),
+            "--speed" => {
+                let value = flag_value(flag, inline, &mut arguments)?;
+                speed = value.parse().ok().filter(|speed: &f64| *speed >= 0.0).ok_or(format!("'{}' is not a valid speed.", value))?;
+            },
+            "--log-level" => {
+                let level = flag_value(flag, inline, &mut arguments)?;
+                options.log_filter = Some(logging::Filter::from_str(&level).map_err(|e| format!("Invalid log level '{}': {}.", level, e))?);
+            },
+            "--socket" => socket = flag_value(flag, inline, &mut arguments)?,
+            flag if flag.starts_with('-') && flag.len() > 1 => return Err(format!("Unknown option '{}'.", flag)),
+            _ => positional.push(argument.clone()),
+        }
+    }
+
+    match (subcommand, positional.as_slice()) {
+        ("eval", [code]) => return Ok(Command::Eval { socket, code: code.clone() }),
+        ("eval", []) => return Err("eval needs the code to evaluate.".into()),
+        ("repl", []) => return Ok(Command::Repl { socket }),
+        ("run" | "check", []) => {},
+        ("run" | "check", [config]) => options.config_location = config.clone(),
+        ("replay", []) => return Err("replay needs the recording to replay.".into()),
+        ("replay", [recording]) => return Ok(Command::Replay { options, recording: recording.clone(), speed }),
+        ("replay", [recording, config]) => {
+            options.config_location = config.clone();
+            return Ok(Command::Replay { options, recording: recording.clone(), speed });
+        },
+        _ => return Err(format!("Unexpected argument '{}'.", positional.last().unwrap())),
+    }
+    match subcommand {
+        "check" => return Ok(Command::Check(options)),
+        _ => return Ok(Command::Run(options)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        return parse(&args.iter().map(|a| a.to_string()).collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn runs_the_default_config_without_arguments() {
+        match parse_args(&[]) {
+            Ok(Command::Run(options)) => assert_eq!(options.config_location, format!("/etc/{}/config.scm", PROGRAM_NAME)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_config_path_on_its_own_runs_it() {
+        match parse_args(&["config.scm"]) {
+            Ok(Command::Run(options)) => assert_eq!(options.config_location, "config.scm"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_flags_with_separate_and_inline_values() {
+        match parse_args(&["check", "--broker", "mqtt.local:1884", "--program=new.scm", "--dry-run", "--no-repl", "config.scm"]) {
+            Ok(Command::Check(options)) => {
+                assert_eq!(options.broker, Some(("mqtt.local".to_string(), Some(1884))));
+                assert_eq!(options.program.as_deref(), Some("new.scm"));
+                assert!(options.dry_run);
+                assert!(options.no_repl);
+                assert_eq!(options.config_location, "config.scm");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn broker_port_is_optional() {
+        match parse_args(&["--broker", "mqtt.local"]) {
+            Ok(Command::Run(options)) => assert_eq!(options.broker, Some(("mqtt.local".to_string(), None))),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replay_takes_a_recording_and_a_speed() {
+        match parse_args(&["replay", "--speed", "10", "traffic.log", "config.scm"]) {
+            Ok(Command::Replay { options, recording, speed }) => {
+                assert_eq!(recording, "traffic.log");
+                assert_eq!(speed, 10.0);
+                assert_eq!(options.config_location, "config.scm");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn eval_takes_the_code_and_a_socket() {
+        match parse_args(&["eval", "--socket", "/tmp/ctl.sock", "(+ 1 2)"]) {
+            Ok(Command::Eval { socket, code }) => {
+                assert_eq!(socket, "/tmp/ctl.sock");
+                assert_eq!(code, "(+ 1 2)");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn help_and_version_win_over_everything_else() {
+        assert!(matches!(parse_args(&["run", "--help"]), Ok(Command::Help)));
+        assert!(matches!(parse_args(&["--version", "--bogus"]), Ok(Command::Version)));
+    }
+
+    #[test]
+    fn refuses_bad_arguments() {
+        assert!(parse_args(&["--bogus"]).is_err());
+        assert!(parse_args(&["--program"]).is_err());
+        assert!(parse_args(&["--broker", "mqtt.local:port"]).is_err());
+        assert!(parse_args(&["replay", "--speed", "-1", "traffic.log"]).is_err());
+        assert!(parse_args(&["--log-level", "loud"]).is_err());
+        assert!(parse_args(&["eval"]).is_err());
+        assert!(parse_args(&["replay"]).is_err());
+        assert!(parse_args(&["a.scm", "b.scm"]).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Reads settings out of the config program. Instead of stopping at the first problem, every
+// wrongly typed or missing setting is collected, so they can all be reported at once.
+//
+// Every setting can be overridden by an environment variable named after it, e.g.
+// HEINZELMANN_BROKER_PORT for broker-port.
+
+use std::{env, fs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use steel::{steel_vm::engine::Engine, SteelVal};
+use crate::{ReplError, PROGRAM_NAME};
+
+fn env_name(name: &str) -> String {
+    return format!("{}_{}", PROGRAM_NAME.to_uppercase(), name.to_uppercase().replace('-', "_"));
+}
+
+pub struct ConfigReader {
+    vm: Engine,
+    location: String,
+    source: String,
+    // Every setting that was looked up, to tell typos apart from helper definitions.
+    known: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl ConfigReader {
+    pub fn new(location: &str, source: String) -> Result<ConfigReader, Vec<String>> {
+        let mut vm = Engine::new();
+        if let Err(e) = vm.compile_and_run_raw_program_with_path(&source, PathBuf::from(location)) {
+            let error = ReplError::from_steel_error(&e, &source, vm.get_source_id(&PathBuf::from(location)));
+            return Err(vec![format!("{}: {}", location, error)]);
+        }
+        return Ok(ConfigReader { vm, location: location.into(), source, known: vec![], errors: vec![] });
+    }
+
+    // The file and line name is defined at, for error messages.
+    fn position(&self, name: &str) -> String {
+        match defines(&self.source).into_iter().find(|(n, _)| n == name) {
+            Some((_, line)) => return format!("{}:{}", self.location, line),
+            None => return self.location.clone(),
+        }
+    }
+
+    fn value(&mut self, name: &str) -> Option<SteelVal> {
+        self.known.push(name.into());
+        return self.vm.extract_value(name).ok();
+    }
+
+    // The value of the environment variable overriding name, if it is set.
+    fn env_value(&mut self, name: &str) -> Option<String> {
+        self.known.push(name.into());
+        return env::var(env_name(name)).ok();
+    }
+
+    fn invalid_env(&mut self, name: &str, expected: &str, value: &str) {
+        let error = format!("{}: should be {}, but is \"{}\".", env_name(name), expected, value);
+        self.errors.push(error);
+    }
+
+    fn invalid(&mut self, name: &str, expected: &str, value: &SteelVal) {
+        let error = format!("{}: '{}' should be {}, but is {}.", self.position(name), name, expected, describe(value));
+        self.errors.push(error);
+    }
+
+    // Records a problem with a setting that has the right type, but an unusable value.
+    pub fn error(&mut self, name: &str, message: &str) {
+        let error = format!("{}: '{}' {}", self.position(name), name, message);
+        self.errors.push(error);
+    }
+
+    pub fn string(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.env_value(name) {
+            return Some(value);
+        }
+        let value = self.value(name)?;
+        match String::try_from(value.clone()) {
+            Ok(s) => return Some(s),
+            Err(_) => {
+                self.invalid(name, "a string", &value);
+                return None;
+            },
+        }
+    }
+
+    pub fn required_string(&mut self, name: &str) -> Option<String> {
+        let value = self.string(name);
+        if value.is_none() && self.vm.extract_value(name).is_err() {
+            self.errors.push(format!("{}: '{}' is required, but isn't defined (nor is {} set).", self.location, name, env_name(name)));
+        }
+        return value;
+    }
+
+    // Reads a secret like a password. Instead of writing it into the config, it can be read from
+    // a file named by "<name>-file", or from a systemd credential called name.
+    pub fn secret(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.string(name) {
+            return Some(value);
+        }
+        let file_setting = format!("{}-file", name);
+        let (setting, path) = match self.string(&file_setting) {
+            Some(path) => (file_setting, path),
+            None => match env::var("CREDENTIALS_DIRECTORY") {
+                Ok(directory) if Path::new(&directory).join(name).exists() => {
+                    let path = Path::new(&directory).join(name).to_string_lossy().to_string();
+                    (name.to_string(), path)
+                },
+                _ => return None,
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(secret) => return Some(secret.trim_end_matches(&['\r', '\n'][..]).to_string()),
+            Err(e) => {
+                self.error(&setting, &format!("is read from {}, which can't be read: {}", path, e));
+                return None;
+            },
+        }
+    }
+
+    pub fn boolean(&mut self, name: &str) -> Option<bool> {
+        if let Some(value) = self.env_value(name) {
+            match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" | "#t" => return Some(true),
+                "0" | "false" | "no" | "off" | "#f" => return Some(false),
+                _ => {
+                    self.invalid_env(name, "true or false", &value);
+                    return None;
+                },
+            }
+        }
+        let value = self.value(name)?;
+        match value {
+            SteelVal::BoolV(b) => return Some(b),
+            _ => {
+                self.invalid(name, "#t or #f", &value);
+                return None;
+            },
+        }
+    }
+
+    // Reads an integer that has to fit into T, e.g. a port number.
+    pub fn integer<T: TryFrom<SteelVal> + FromStr>(&mut self, name: &str, expected: &str) -> Option<T> {
+        if let Some(value) = self.env_value(name) {
+            match value.parse() {
+                Ok(n) => return Some(n),
+                Err(_) => {
+                    self.invalid_env(name, expected, &value);
+                    return None;
+                },
+            }
+        }
+        let value = self.value(name)?;
+        match T::try_from(value.clone()) {
+            Ok(n) => return Some(n),
+            Err(_) => {
+                self.invalid(name, expected, &value);
+                return None;
+            },
+        }
+    }
+
+    // Reads a limit that can be switched off with #f. Returns None if it isn't defined at all.
+    pub fn limit(&mut self, name: &str) -> Option<Option<u64>> {
+        if let Some(value) = self.env_value(name) {
+            match value.as_str() {
+                "false" | "off" | "#f" => return Some(None),
+                _ => match value.parse() {
+                    Ok(n) => return Some(Some(n)),
+                    Err(_) => {
+                        self.invalid_env(name, "a positive integer or false", &value);
+                        return None;
+                    },
+                },
+            }
+        }
+        let value = self.value(name)?;
+        match value {
+            SteelVal::BoolV(false) => return Some(None),
+            _ => match u64::try_from(value.clone()) {
+                Ok(n) => return Some(Some(n)),
+                Err(_) => {
+                    self.invalid(name, "a positive integer or #f", &value);
+                    return None;
+                },
+            },
+        }
+    }
+
+    // Reads a setting that is either a string or #f to switch something off. Returns None if it
+    // isn't defined at all.
+    pub fn boolean_or_string(&mut self, name: &str) -> Option<Option<String>> {
+        if let Some(value) = self.env_value(name) {
+            match value.as_str() {
+                "false" | "off" | "#f" => return Some(None),
+                _ => return Some(Some(value)),
+            }
+        }
+        let value = self.value(name)?;
+        match value {
+            SteelVal::BoolV(false) => return Some(None),
+            SteelVal::StringV(s) => return Some(Some(s.to_string())),
+            _ => {
+                self.invalid(name, "a string or #f", &value);
+                return None;
+            },
+        }
+    }
+
+    // Lists are given as comma separated values in the environment.
+    pub fn string_list(&mut self, name: &str) -> Option<Vec<String>> {
+        if let Some(value) = self.env_value(name) {
+            return Some(value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect());
+        }
+        let value = self.value(name)?;
+        let strings: Option<Vec<String>> = value.list()
+            .and_then(|list| list.into_iter().map(|s| String::try_from(s).ok()).collect());
+        if strings.is_none() {
+            self.invalid(name, "a list of strings", &value);
+        }
+        return strings;
+    }
+
+    // Reads topic filters to subscribe to, each given either as a string or as a list of the
+    // filter and the QoS to subscribe with, which defaults to 0. In the environment, the QoS
+    // follows the filter after a colon, as in "sensors/#:1".
+    pub fn subscriptions(&mut self, name: &str) -> Option<Vec<(String, u8)>> {
+        if let Some(value) = self.env_value(name) {
+            let mut subscriptions = vec![];
+            for subscription in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                match subscription.rsplit_once(':').map(|(topic, qos)| (topic, qos.parse::<u8>())) {
+                    Some((topic, Ok(qos))) if qos <= 2 => subscriptions.push((topic.to_string(), qos)),
+                    Some(_) => self.invalid_env(name, "a list of topic filters with an optional QoS of 0, 1 or 2", &value),
+                    None => subscriptions.push((subscription.to_string(), 0)),
+                }
+            }
+            return Some(subscriptions);
+        }
+        let value = self.value(name)?;
+        let subscriptions: Option<Vec<(String, u8)>> = value.list()
+            .and_then(|list| list.into_iter().map(|subscription| match subscription {
+                SteelVal::StringV(topic) => Some((topic.to_string(), 0)),
+                _ => match subscription.list()?.as_slice() {
+                    [SteelVal::StringV(topic), qos] => match u8::try_from(qos.clone()) {
+                        Ok(qos) if qos <= 2 => Some((topic.to_string(), qos)),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+            }).collect());
+        if subscriptions.is_none() {
+            self.invalid(name, "a list of topic filters, or of lists of a topic filter and a QoS of 0, 1 or 2", &value);
+        }
+        return subscriptions;
+    }
+
+    // Returns warnings about settings that look like misspelled ones, or every error found.
+    pub fn finish(self) -> Result<Vec<String>, Vec<String>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        let mut warnings = vec![];
+        for (name, line) in defines(&self.source) {
+            if self.known.contains(&name) {
+                continue;
+            }
+            let similar = self.known.iter()
+                .map(|known| (edit_distance(&name, known), known))
+                .filter(|(distance, _)| *distance <= 2)
+                .min_by_key(|(distance, _)| *distance);
+            if let Some((_, known)) = similar {
+                warnings.push(format!("{}:{}: Unknown setting '{}', did you mean '{}'?", self.location, line, name, known));
+            }
+        }
+        let prefix = format!("{}_", PROGRAM_NAME.to_uppercase());
+        for (variable, _) in env::vars() {
+            if variable.starts_with(&prefix) && !self.known.iter().any(|known| env_name(known) == variable) {
+                warnings.push(format!("{} doesn't override any setting.", variable));
+            }
+        }
+        return Ok(warnings);
+    }
+}
+
+fn describe(value: &SteelVal) -> String {
+    match value {
+        SteelVal::StringV(s) => return format!("the string \"{}\"", s),
+        SteelVal::BoolV(true) => return "#t".into(),
+        SteelVal::BoolV(false) => return "#f".into(),
+        SteelVal::IntV(n) => return format!("the number {}", n),
+        SteelVal::NumV(n) => return format!("the number {}", n),
+        SteelVal::ListV(_) => return "a list".into(),
+        v if v.is_function() => return "a function".into(),
+        _ => return "something else".into(),
+    }
+}
+
+// The names of all (define name ...) forms in source along with their line numbers. Comments,
+// strings and character literals are skipped.
+fn defines(source: &str) -> Vec<(String, usize)> {
+    let mut defines = vec![];
+    let mut line = 1;
+    let mut chars = source.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            ';' => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+            },
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => if let Some((_, '\n')) = chars.next() {
+                            line += 1;
+                        },
+                        '\n' => line += 1,
+                        '"' => break,
+                        _ => {},
+                    }
+                }
+            },
+            '#' if chars.peek().map(|(_, c)| *c) == Some('\\') => {
+                chars.next();
+                chars.next();
+            },
+            '(' => {
+                let rest = match source[index..].strip_prefix("(define") {
+                    Some(rest) if rest.starts_with(char::is_whitespace) => rest,
+                    _ => continue,
+                };
+                let name: String = rest.trim_start()
+                    .chars()
+                    .take_while(|c| !c.is_whitespace() && *c != '(' && *c != ')')
+                    .collect();
+                if !name.is_empty() {
+                    defines.push((name, line));
+                }
+            },
+            _ => {},
+        }
+    }
+    return defines;
+}
+
+// The number of single character insertions, deletions and substitutions to turn a into b.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    return previous[b.len()];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(source: &str, known: &[&str]) -> ConfigReader {
+        return ConfigReader {
+            vm: Engine::new(),
+            location: "config.scm".into(),
+            source: source.into(),
+            known: known.iter().map(|k| k.to_string()).collect(),
+            errors: vec![],
+        };
+    }
+
+    #[test]
+    fn finds_definitions_with_their_lines() {
+        let source = "(define broker-addr \"localhost\")\n\n(define (helper x)\n  (define inner x)\n  inner) (define keep-alive 5)\n";
+        assert_eq!(defines(source), vec![("broker-addr".into(), 1), ("inner".into(), 4), ("keep-alive".into(), 5)]);
+    }
+
+    #[test]
+    fn skips_definitions_in_comments_and_strings() {
+        let source = ";; (define broker-port 1883)\n(define greeting \"(define not-a-setting 1)\") ; (define nor-this 2)\n(define text \"a ; b\nc\") (define inflight 10)\n(define paren #\\() (define quote #\\\") (define clean-session #t)\n";
+        assert_eq!(defines(source), vec![
+            ("greeting".into(), 2),
+            ("text".into(), 3),
+            ("inflight".into(), 4),
+            ("paren".into(), 5),
+            ("quote".into(), 5),
+            ("clean-session".into(), 5),
+        ]);
+    }
+
+    #[test]
+    fn measures_edit_distance() {
+        assert_eq!(edit_distance("broker-port", "broker-port"), 0);
+        assert_eq!(edit_distance("broker-prot", "broker-port"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn warns_about_misspelled_settings_only() {
+        let reader = reader("(define broker-adr \"localhost\")\n(define my-helper 1)\n(define keep-alive 5)\n", &["broker-addr", "keep-alive"]);
+        let warnings: Vec<String> = reader.finish().unwrap().into_iter().filter(|w| w.starts_with("config.scm")).collect();
+        assert_eq!(warnings, vec!["config.scm:1: Unknown setting 'broker-adr', did you mean 'broker-addr'?".to_string()]);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let mut reader = reader("(define inflight 0)\n", &[]);
+        reader.error("inflight", "should be at least 1.");
+        reader.error("nrepl", "should only contain IP addresses.");
+        assert_eq!(reader.finish().unwrap_err(), vec![
+            "config.scm:1: 'inflight' should be at least 1.".to_string(),
+            "config.scm: 'nrepl' should only contain IP addresses.".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,225 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// A client for the control socket of a running heinzelmann, used by `heinzelmann eval` and
+// `heinzelmann repl`.
+
+use std::cell::RefCell;
+use std::io::{self, prelude::*, BufReader};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::{Serialize, Deserialize};
+use crate::nrepl;
+use crate::repl::{self, CompletionSource};
+
+pub const DEFAULT_SOCKET: &'static str = "/run/heinzelmann/control.sock";
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct ControlRequest<'a> {
+    op: &'a str,
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<&'a str>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct ControlResponse {
+    id: Option<u64>,
+    new_session: Option<String>,
+    value: Option<String>,
+    out: Option<String>,
+    err: Option<String>,
+    status: Vec<String>,
+    completions: Vec<ControlCompletion>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct ControlCompletion {
+    candidate: String,
+}
+
+// Requests use ids from 1 on, interrupts sent on Ctrl-C always use 0.
+const INTERRUPT_ID: u64 = 0;
+
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    // Shared with the Ctrl-C handler, which sends interrupts while an evaluation runs.
+    writer: Arc<Mutex<UnixStream>>,
+    session: String,
+    next_id: u64,
+    interrupting: Arc<AtomicBool>,
+}
+
+impl ControlClient {
+    pub fn connect(path: &str) -> io::Result<ControlClient> {
+        let stream = UnixStream::connect(path)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let writer = Arc::new(Mutex::new(stream));
+        let mut client = ControlClient { reader, writer, session: String::new(), next_id: 1, interrupting: Arc::new(AtomicBool::new(false)) };
+
+        let mut session = None;
+        client.request("clone", None, None, |response| {
+            if let Some(new_session) = &response.new_session {
+                session = Some(new_session.clone());
+            }
+        })?;
+        client.session = session.ok_or(io::Error::new(io::ErrorKind::InvalidData, "no session was created"))?;
+        return Ok(client);
+    }
+
+    // Ctrl-C interrupts the evaluation running in the session. Pressed again before the
+    // evaluation ends, it gives up on it and exits.
+    pub fn handle_ctrl_c(&self) {
+        let writer = self.writer.clone();
+        let session = self.session.clone();
+        let interrupting = self.interrupting.clone();
+        repl::handle_ctrl_c(move || {
+            if interrupting.swap(true, Ordering::Relaxed) {
+                process::exit(130);
+            }
+            let request = ControlRequest { op: "interrupt", id: INTERRUPT_ID, session: Some(&session), code: None, prefix: None };
+            if let Ok(encoded_data) = bt_bencode::to_vec(&request) {
+                let _ = writer.lock().unwrap().write_all(&encoded_data);
+            }
+        });
+    }
+
+    fn send(&mut self, op: &str, code: Option<&str>, prefix: Option<&str>) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let session = match self.session.is_empty() {
+            true => None,
+            false => Some(self.session.as_str()),
+        };
+        let request = ControlRequest { op, id, session, code, prefix };
+        let encoded_data = bt_bencode::to_vec(&request)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.writer.lock().unwrap().write_all(&encoded_data)?;
+        return Ok(id);
+    }
+
+    // Sends a request and hands every response to it to on_response, until it is done.
+    fn request<F: FnMut(&ControlResponse)>(&mut self, op: &str, code: Option<&str>, prefix: Option<&str>, mut on_response: F) -> io::Result<()> {
+        let id = self.send(op, code, prefix)?;
+        loop {
+            let message = match nrepl::read_message(&mut self.reader)? {
+                Some(message) => message,
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+            let response: ControlResponse = bt_bencode::from_slice(&message)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            if response.id != Some(id) {
+                continue;
+            }
+            on_response(&response);
+            if response.status.iter().any(|s| s == "done") {
+                return Ok(());
+            }
+        }
+    }
+
+    // Evaluates code, printing its output and result as they arrive. Returns whether the
+    // evaluation succeeded.
+    pub fn eval(&mut self, code: &str) -> io::Result<bool> {
+        let mut succeeded = true;
+        self.interrupting.store(false, Ordering::Relaxed);
+        self.request("eval", Some(code), None, |response| {
+            if let Some(out) = &response.out {
+                print!("{}", out);
+                let _ = io::stdout().flush();
+            }
+            if let Some(err) = &response.err {
+                eprint!("{}", err);
+            }
+            if let Some(value) = &response.value {
+                println!("=> {}", value);
+            }
+            if response.status.iter().any(|s| s == "eval-error") {
+                succeeded = false;
+            }
+            if response.status.iter().any(|s| s == "interrupted") {
+                println!("Interrupted.");
+                succeeded = false;
+            }
+        })?;
+        return Ok(succeeded);
+    }
+
+    pub fn completions(&mut self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut candidates = vec![];
+        self.request("completions", None, Some(prefix), |response| {
+            candidates.extend(response.completions.iter().map(|c| c.candidate.clone()));
+        })?;
+        return Ok(candidates);
+    }
+
+    pub fn close(&mut self) {
+        let _ = self.send("close", None, None);
+    }
+}
+
+// Evaluates code in the running instance and returns the exit code.
+pub fn eval(socket: &str, code: &str) -> i32 {
+    let mut client = match ControlClient::connect(socket) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Unable to connect to {}: {}", socket, e);
+            return 1;
+        },
+    };
+    client.handle_ctrl_c();
+    let exit_code = match client.eval(code) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("Lost connection to {}: {}", socket, e);
+            1
+        },
+    };
+    client.close();
+    return exit_code;
+}
+
+// Runs an interactive REPL against the running instance and returns the exit code.
+pub fn repl(socket: &str, history_file: Option<String>) -> i32 {
+    let client = match ControlClient::connect(socket) {
+        Ok(client) => Rc::new(RefCell::new(client)),
+        Err(e) => {
+            eprintln!("Unable to connect to {}: {}", socket, e);
+            return 1;
+        },
+    };
+    println!("Connected to {}.", socket);
+    client.borrow().handle_ctrl_c();
+
+    let completion_client = client.clone();
+    let complete: CompletionSource = Box::new(move |prefix| {
+        return completion_client.borrow_mut().completions(prefix).unwrap_or_default();
+    });
+    let mut editor = repl::create_editor(complete, &history_file);
+
+    while let Some(line) = repl::read_input(&mut editor, &history_file) {
+        if line.starts_with("(quit)") {
+            break;
+        }
+        if let Err(e) = client.borrow_mut().eval(&line) {
+            eprintln!("Lost connection to {}: {}", socket, e);
+            return 1;
+        }
+    }
+    client.borrow_mut().close();
+    return 0;
+}
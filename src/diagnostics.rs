@@ -0,0 +1,131 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// heinzelmann's own topic tree under <prefix>/heinzelmann, so it can be watched and controlled
+// from MQTT dashboards:
+//
+//   status           "online", or "offline" once the connection is lost (retained)
+//   version          the running version (retained)
+//   program          md5 hash of the loaded program (retained)
+//   hooks, timers    the registered hooks and timers (retained)
+//   uptime           seconds since starting, published every minute
+//   errors           a JSON object for every handler that failed
+//   command          accepts "reload"
+//   command/result   the outcome of each command
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use rumqttc::{Client, LastWill, QoS};
+use chrono::Local;
+use crate::{utils, VMMessage, ReplResponse, PROGRAM_NAME};
+use crate::repl::MetaCommand;
+use crate::status::Status;
+
+const UPTIME_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn topic(prefix: &str, name: &str) -> String {
+    return format!("{}/{}/{}", prefix, PROGRAM_NAME, name);
+}
+
+// Marks heinzelmann as offline when the broker loses the connection.
+pub fn last_will(prefix: &str) -> LastWill {
+    return LastWill::new(topic(prefix, "status"), "offline", QoS::AtLeastOnce, true);
+}
+
+// Called from the connection loop whenever the broker (re)connects. The connection loop can't
+// wait for the client, so nothing here blocks.
+pub fn connected(client: &Client, prefix: &str) {
+    let _ = client.try_publish(topic(prefix, "status"), QoS::AtLeastOnce, true, "online");
+    let _ = client.try_publish(topic(prefix, "version"), QoS::AtLeastOnce, true, env!("CARGO_PKG_VERSION"));
+    if let Err(e) = client.try_subscribe(topic(prefix, "command"), QoS::AtLeastOnce) {
+        log::warn!("Unable to subscribe to the command topic: {}", e);
+    }
+}
+
+// Runs a command received on the command topic and publishes its outcome.
+pub fn handle_command(command: &str, tx: &mpsc::Sender<VMMessage>, client: &Client, prefix: &str) {
+    let result_topic = topic(prefix, "command/result");
+    match command.trim() {
+        "reload" => {
+            log::info!("Reloading the program, as requested on {}.", topic(prefix, "command"));
+            let (resp_tx, resp_rx) = mpsc::channel();
+            tx.send(VMMessage::Meta(MetaCommand::Reload, resp_tx)).unwrap();
+            // The answer has to be waited for without holding up the connection loop.
+            let client = client.clone();
+            thread::spawn(move || {
+                // Output the program prints while loading comes first.
+                let result = match resp_rx.iter().find(|r| !matches!(r, ReplResponse::Stdout(_) | ReplResponse::Stderr(_))) {
+                    Some(ReplResponse::Error(e)) => format!("reload failed: {}", e),
+                    Some(_) => "reload ok".into(),
+                    None => "reload failed".into(),
+                };
+                let _ = client.publish(result_topic, QoS::AtLeastOnce, false, result);
+            });
+        },
+        command => {
+            log::warn!("Unknown command '{}' on {}.", command, topic(prefix, "command"));
+            let _ = client.try_publish(result_topic, QoS::AtLeastOnce, false, format!("unknown command '{}'", command));
+        },
+    }
+}
+
+// Publishes from inside the VM, which knows about the program, its hooks and its errors. The VM
+// must not wait for the connection, so when the client's queue is full the update is dropped.
+pub struct StatusTopics {
+    prefix: String,
+    client: Client,
+    // What was last published on each retained topic, so unchanged values aren't sent again.
+    published: HashMap<String, String>,
+    last_uptime: Option<Instant>,
+}
+
+impl StatusTopics {
+    pub fn new(prefix: String, client: Client) -> StatusTopics {
+        return StatusTopics { prefix, client, published: HashMap::new(), last_uptime: None };
+    }
+
+    fn publish(&self, name: &str, payload: String, retain: bool) -> bool {
+        if let Err(e) = self.client.try_publish(topic(&self.prefix, name), QoS::AtLeastOnce, retain, payload) {
+            log::debug!("Unable to publish on {}: {}", topic(&self.prefix, name), e);
+            return false;
+        }
+        return true;
+    }
+
+    // A dropped update is tried again the next time.
+    fn publish_changed(&mut self, name: &str, payload: String) {
+        if self.published.get(name) == Some(&payload) {
+            return;
+        }
+        if self.publish(name, payload.clone(), true) {
+            self.published.insert(name.into(), payload);
+        }
+    }
+
+    pub fn program_loaded(&mut self, program: &str) {
+        self.publish_changed("program", utils::get_md5(vec![program.into()]));
+    }
+
+    pub fn handler_error(&self, code: &str, error: &str) {
+        let payload = format!("{{\"time\":{},\"handler\":{},\"error\":{}}}",
+            utils::json_string(&Local::now().to_rfc3339()),
+            utils::json_string(code),
+            utils::json_string(error));
+        self.publish("errors", payload, false);
+    }
+
+    // Brings the hooks and timers up to date, and the uptime once a minute.
+    pub fn refresh(&mut self, hooks: String, status: &Status) {
+        self.publish_changed("hooks", hooks);
+        self.publish_changed("timers", status.timers_listing());
+        if self.last_uptime.map_or(true, |last| last.elapsed() >= UPTIME_INTERVAL) {
+            self.publish("uptime", status.uptime().as_secs().to_string(), false);
+            self.last_uptime = Some(Instant::now());
+        }
+    }
+}
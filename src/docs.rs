@@ -0,0 +1,199 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Documentation for the functions heinzelmann adds to the Steel environment, as served to
+// REPL clients looking up a symbol.
+
+use steel::{steel_vm::engine::Engine, SteelVal};
+
+// The version of the steel-core revision pinned in Cargo.toml.
+pub const STEEL_VERSION: &'static str = "0.5.0";
+
+pub struct BuiltinDoc {
+    pub name: &'static str,
+    pub arglists: &'static str,
+    pub doc: &'static str,
+}
+
+pub const BUILTINS: &[BuiltinDoc] = &[
+    BuiltinDoc {
+        name: "send-simple",
+        arglists: "(topic payload)",
+        doc: "Publishes payload on topic.",
+    },
+    BuiltinDoc {
+        name: "send-retain",
+        arglists: "(topic payload)",
+        doc: "Publishes payload on topic as a retained message.",
+    },
+    BuiltinDoc {
+        name: "subscribe",
+        arglists: "(topic)",
+        doc: "Subscribes to topic, which may contain wildcards.",
+    },
+    BuiltinDoc {
+        name: "set-timer",
+        arglists: "(time id)",
+        doc: "Fires the timer hook registered as id every day at time, given as \"HH:MM\".",
+    },
+    BuiltinDoc {
+        name: "register-event!",
+        arglists: "(topic f)",
+        doc: "Calls (f topic payload) for messages on topic. Topics ending in /# also match all subtopics.",
+    },
+    BuiltinDoc {
+        name: "register-json-event!",
+        arglists: "(topic f)",
+        doc: "Like register-event!, but passes the payload to f parsed as JSON.",
+    },
+    BuiltinDoc {
+        name: "register-timer!",
+        arglists: "(id f)",
+        doc: "Calls (f) whenever the timer id fires.",
+    },
+    BuiltinDoc {
+        name: "handle-event",
+        arglists: "(topic payload)",
+        doc: "Runs the event hook matching topic, as if the message had arrived from the broker.",
+    },
+    BuiltinDoc {
+        name: "handle-timer",
+        arglists: "(id)",
+        doc: "Runs the timer hook registered as id.",
+    },
+    BuiltinDoc {
+        name: "json-ref",
+        arglists: "(value key ...)",
+        doc: "Follows keys and list indices into a parsed JSON value, returning #f if any of them is missing.",
+    },
+    BuiltinDoc {
+        name: "random-string",
+        arglists: "(length)",
+        doc: "Returns a random lowercase alphanumeric string of the given length.",
+    },
+    BuiltinDoc {
+        name: "current-timestamp",
+        arglists: "()",
+        doc: "Returns the current unix timestamp in seconds.",
+    },
+    BuiltinDoc {
+        name: "md5",
+        arglists: "(strings)",
+        doc: "Returns the hex encoded md5 hash of a list of strings.",
+    },
+    BuiltinDoc {
+        name: "log-debug",
+        arglists: "(arg ...)",
+        doc: "Logs the arguments, joined like displayln does, at debug level under the program target.",
+    },
+    BuiltinDoc {
+        name: "log-info",
+        arglists: "(arg ...)",
+        doc: "Logs the arguments, joined like displayln does, at info level under the program target.",
+    },
+    BuiltinDoc {
+        name: "log-warn",
+        arglists: "(arg ...)",
+        doc: "Logs the arguments, joined like displayln does, at warn level under the program target.",
+    },
+    BuiltinDoc {
+        name: "log-error",
+        arglists: "(arg ...)",
+        doc: "Logs the arguments, joined like displayln does, at error level under the program target.",
+    },
+    BuiltinDoc {
+        name: "counter-add!",
+        arglists: "(name amount)",
+        doc: "Adds amount to the counter name, served as heinzelmann_program_name on the metrics endpoint.",
+    },
+    BuiltinDoc {
+        name: "counter-inc!",
+        arglists: "(name)",
+        doc: "Adds one to the counter name.",
+    },
+    BuiltinDoc {
+        name: "gauge-set!",
+        arglists: "(name value)",
+        doc: "Sets the gauge name, served as heinzelmann_program_name on the metrics endpoint.",
+    },
+];
+
+pub fn find_builtin(name: &str) -> Option<&'static BuiltinDoc> {
+    return BUILTINS.iter().find(|b| b.name == name);
+}
+
+#[derive(Clone, Debug)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub kind: String,
+    pub arglists: String,
+    pub doc: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Completion {
+    pub candidate: String,
+    pub kind: String,
+}
+
+fn kind_of(value: &SteelVal) -> String {
+    match value.is_function() {
+        true => "function".into(),
+        false => "var".into(),
+    }
+}
+
+// Globals of the VM starting with prefix. Heinzelmann's own functions are always offered, even
+// before they are registered once the broker and timers are ready.
+pub fn completions(vm: &Engine, prefix: &str) -> Vec<Completion> {
+    let mut candidates: Vec<String> = vm.globals().iter()
+        .map(|s| s.resolve().to_string())
+        .filter(|s| !s.starts_with("#%") && !s.contains("__"))
+        .chain(BUILTINS.iter().map(|b| b.name.to_string()))
+        .filter(|s| s.starts_with(prefix))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    return candidates.into_iter()
+        .map(|candidate| {
+            let kind = match vm.extract_value(&candidate) {
+                Ok(value) => kind_of(&value),
+                Err(_) => "function".into(),
+            };
+            Completion { candidate, kind }
+        })
+        .collect();
+}
+
+// Functions defined in Scheme know how many arguments they take, though not their names.
+fn arglists_of(value: &SteelVal) -> String {
+    match value {
+        SteelVal::Closure(lambda) => {
+            let arguments: Vec<String> = (1..=lambda.arity()).map(|n| format!("arg{}", n)).collect();
+            return format!("({})", arguments.join(" "));
+        },
+        _ => return String::new(),
+    }
+}
+
+pub fn lookup(vm: &Engine, symbol: &str) -> Option<SymbolInfo> {
+    if let Some(builtin) = find_builtin(symbol) {
+        return Some(SymbolInfo {
+            name: builtin.name.into(),
+            kind: "function".into(),
+            arglists: builtin.arglists.into(),
+            doc: builtin.doc.into(),
+        });
+    }
+    let value = vm.extract_value(symbol).ok()?;
+    return Some(SymbolInfo {
+        name: symbol.into(),
+        kind: kind_of(&value),
+        arglists: arglists_of(&value),
+        doc: String::new(),
+    });
+}
@@ -0,0 +1,218 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Log messages from heinzelmann itself and from the program. Every message has a level and a
+// target, which is the module it comes from ("nrepl", "config", ...) or "program" for the
+// program's own log-info and friends.
+
+use std::env;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::RwLock;
+use chrono::Local;
+use log::{Log, Metadata, Record, Level, LevelFilter};
+use crate::{utils, PROGRAM_NAME};
+
+// The target of messages logged by the program.
+pub const PROGRAM_TARGET: &'static str = "program";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    // Plain messages with a syslog priority prefix, which journald turns into the level.
+    Journald,
+}
+
+impl Format {
+    // Journald when running as a systemd service, text otherwise.
+    pub fn detect() -> Format {
+        match env::var_os("JOURNAL_STREAM") {
+            Some(_) => return Format::Journald,
+            None => return Format::Text,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Format, ()> {
+        match s {
+            "text" => return Ok(Format::Text),
+            "json" => return Ok(Format::Json),
+            "journald" => return Ok(Format::Journald),
+            _ => return Err(()),
+        }
+    }
+}
+
+// Which levels are logged, like "info,nrepl=debug,program=warn": a default level, followed by
+// levels for single targets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub const fn new(default: LevelFilter) -> Filter {
+        return Filter { default, targets: Vec::new() };
+    }
+
+    // The most specific level given for target.
+    fn level(&self, target: &str) -> LevelFilter {
+        return self.targets.iter()
+            .filter(|(t, _)| target == t || target.starts_with(&format!("{}::", t)))
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |(_, level)| *level);
+    }
+
+    // The highest level any target is logged at.
+    fn max_level(&self) -> LevelFilter {
+        return self.targets.iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max);
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let parse_level = |level: &str| LevelFilter::from_str(level.trim())
+            .map_err(|_| format!("'{}' is not one of off, error, warn, info, debug or trace", level.trim()));
+        let mut filter = Filter::new(LevelFilter::Info);
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => filter.targets.push((target.trim().into(), parse_level(level)?)),
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        return Ok(filter);
+    }
+}
+
+// Module paths are logged without the crate name, so "heinzelmann::nrepl" becomes "nrepl".
+fn short_target(target: &str) -> &str {
+    return target.strip_prefix(&format!("{}::", PROGRAM_NAME)).unwrap_or(target);
+}
+
+// The syslog priority of a level, see sd-daemon(3).
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => return 3,
+        Level::Warn => return 4,
+        Level::Info => return 6,
+        Level::Debug | Level::Trace => return 7,
+    }
+}
+
+fn format_record(format: Format, record: &Record) -> String {
+    let target = short_target(record.target());
+    match format {
+        Format::Text => return format!("{} {:<5} [{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), target, record.args()),
+        Format::Json => return format!("{{\"time\":{},\"level\":{},\"target\":{},\"message\":{}}}",
+            utils::json_string(&Local::now().to_rfc3339()),
+            utils::json_string(&record.level().as_str().to_lowercase()),
+            utils::json_string(target),
+            utils::json_string(&record.args().to_string())),
+        // Every line gets the prefix, since journald treats each line as its own entry.
+        Format::Journald => return record.args().to_string().lines()
+            .map(|line| format!("<{}>[{}] {}", priority(record.level()), target, line))
+            .collect::<Vec<String>>()
+            .join("\n"),
+    }
+}
+
+struct Logger {
+    settings: RwLock<(Filter, Format)>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let settings = self.settings.read().unwrap();
+        return metadata.level() <= settings.0.level(short_target(metadata.target()));
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(self.settings.read().unwrap().1, record);
+        let _ = writeln!(io::stdout().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+static LOGGER: Logger = Logger { settings: RwLock::new((Filter::new(LevelFilter::Info), Format::Text)) };
+
+pub fn init(filter: Filter, format: Format) {
+    let _ = log::set_logger(&LOGGER);
+    configure(filter, format);
+}
+
+// Replaces the filter and format, once they are known from the config.
+pub fn configure(filter: Filter, format: Format) {
+    log::set_max_level(filter.max_level());
+    *LOGGER.settings.write().unwrap() = (filter, format);
+}
+
+// Logs a message from the program at the named level.
+pub fn log_program(level: &str, message: &str) {
+    let level = Level::from_str(level).unwrap_or(Level::Info);
+    log::log!(target: PROGRAM_TARGET, level, "{}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_levels_beat_the_default() {
+        let filter = Filter::from_str("warn,nrepl=trace,program=error").unwrap();
+        assert_eq!(filter.level("nrepl"), LevelFilter::Trace);
+        assert_eq!(filter.level("program"), LevelFilter::Error);
+        assert_eq!(filter.level("config"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn the_most_specific_target_wins() {
+        let filter = Filter::from_str("nrepl=warn,nrepl::sessions=debug").unwrap();
+        assert_eq!(filter.level("nrepl::sessions"), LevelFilter::Debug);
+        assert_eq!(filter.level("nrepl::other"), LevelFilter::Warn);
+        // A target only covers its own modules, not others that start with the same letters.
+        assert_eq!(filter.level("nreplx"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn parses_with_spaces_and_empty_directives() {
+        let filter = Filter::from_str(" debug , nrepl = off ,").unwrap();
+        assert_eq!(filter.level("config"), LevelFilter::Debug);
+        assert_eq!(filter.level("nrepl"), LevelFilter::Off);
+        assert_eq!(Filter::from_str("").unwrap(), Filter::new(LevelFilter::Info));
+    }
+
+    #[test]
+    fn refuses_unknown_levels() {
+        assert!(Filter::from_str("loud").unwrap_err().contains("'loud'"));
+        assert!(Filter::from_str("info,nrepl=verbose").unwrap_err().contains("'verbose'"));
+    }
+
+    #[test]
+    fn journald_lines_all_get_the_priority() {
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("heinzelmann::nrepl")
+            .args(format_args!("first\nsecond"))
+            .build();
+        assert_eq!(format_record(Format::Journald, &record), "<4>[nrepl] first\n<4>[nrepl] second");
+    }
+}
@@ -4,23 +4,48 @@
 * You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>. 
 */
 
-use std::{env, fs};
-use std::sync::mpsc;
+use std::{env, fs, process};
+use std::cell::RefCell;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::io::stdout;
 use std::io::prelude::*;
-use steel::{steel_vm::engine::Engine, SteelVal};
+use steel::{steel_vm::engine::Engine, SteelVal, SteelErr};
+use steel::rerrs::ErrorKind;
+use steel::parser::parser::SourceId;
 use steel::steel_vm::register_fn::RegisterFn;
 use steel_derive::Steel;
-use std::time::Duration;
-use rumqttc::{MqttOptions,  Client, Connection, Event, Packet};
+use std::time::{Duration, Instant};
+use rumqttc::{MqttOptions,  Client, Connection, Event, Packet, QoS};
+use log::LevelFilter;
+use std::str::FromStr;
 use chrono::{DateTime, Local};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::net::IpAddr;
+use config::ConfigReader;
+use status::{Status, SharedStatus};
+use audit::{Audit, SharedAudit};
+use repl::MetaCommand;
 
 mod utils; 
 mod nrepl; 
+mod docs;
+mod repl;
+mod status;
+mod ctl;
+mod cli;
+mod logging;
+mod config;
+mod metrics;
+mod systemd;
+mod diagnostics;
+mod audit;
+mod recording;
 
 const PROGRAM_NAME: &'static str = "heinzelmann";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Clone, Debug, Steel, PartialEq)]
 pub struct TimedEvent {
@@ -39,10 +64,13 @@ impl TimedEvent {
         return Self::new((hours, minutes), topic);
     }
     fn get_next_time(&self) -> DateTime<Local> {
+        return self.next_time_after(Local::now());
+    }
+    fn next_time_after(&self, now: DateTime<Local>) -> DateTime<Local> {
         let (hours, minutes) = self.time;
-        let time = Local::now().date_naive().and_hms_opt(hours, minutes, 0).unwrap();
+        let time = now.date_naive().and_hms_opt(hours, minutes, 0).unwrap();
         let mut time = time.and_local_timezone(Local).unwrap();
-        if Local::now().time() - time.time() >= chrono::Duration::minutes(0) {
+        if now.time() - time.time() >= chrono::Duration::minutes(0) {
             time = time + chrono::Duration::days(1);
         }
         return time;
@@ -56,67 +84,159 @@ struct Configuration {
     port: u16,
     user: Option<String>,
     password: Option<String>,
+    subscriptions: Vec<(String, QoS)>,
+    keep_alive: u64,
+    clean_session: bool,
+    inflight: u16,
+    log_filter: logging::Filter,
+    log_format: logging::Format,
+    data_dir: String,
     local_repl: bool,
-    nrepl: Vec<String>,
+    repl_history_file: Option<String>,
+    nrepl: Option<nrepl::NReplConfiguration>,
+    control_socket: Option<nrepl::ControlSocketConfiguration>,
+    metrics: Option<metrics::MetricsConfiguration>,
+    metric_filters: Vec<String>,
+    status_prefix: Option<String>,
+    audit: Option<audit::AuditConfiguration>,
+    record_file: Option<String>,
+    dry_run: bool,
+    handler_budget: EvalBudget,
+    repl_budget: EvalBudget,
 }
 
 impl Configuration {
-    fn new(id: String, program_location: String, addr: String, port: u16, user: Option<String>, password: Option<String>, local_repl: bool, nrepl: Vec<String>) -> Configuration {
-        return Configuration { id, program_location, addr, port, user, password, local_repl, nrepl };
+    fn new(id: String, program_location: String, addr: String, port: u16, user: Option<String>, password: Option<String>, subscriptions: Vec<(String, QoS)>, keep_alive: u64, clean_session: bool, inflight: u16, log_filter: logging::Filter, log_format: logging::Format, data_dir: String, local_repl: bool, repl_history_file: Option<String>, nrepl: Option<nrepl::NReplConfiguration>, control_socket: Option<nrepl::ControlSocketConfiguration>, metrics: Option<metrics::MetricsConfiguration>, metric_filters: Vec<String>, status_prefix: Option<String>, audit: Option<audit::AuditConfiguration>, record_file: Option<String>, dry_run: bool, handler_budget: EvalBudget, repl_budget: EvalBudget) -> Configuration {
+        return Configuration { id, program_location, addr, port, user, password, subscriptions, keep_alive, clean_session, inflight, log_filter, log_format, data_dir, local_repl, repl_history_file, nrepl, control_socket, metrics, metric_filters, status_prefix, audit, record_file, dry_run, handler_budget, repl_budget };
     }
 
-    fn from_config_program(program: String) -> Configuration {
-        let mut vm = Engine::new();
-        vm.compile_and_run_raw_program(&program).unwrap();
+    fn from_config_program(location: &str, program: String, options: &cli::Options) -> Result<Configuration, Vec<String>> {
+        let mut reader = ConfigReader::new(location, program)?;
+
+        let mut id = reader.string("client-id").unwrap_or(PROGRAM_NAME.into());
+        // A random suffix lets several instances share a config without kicking each other off
+        // the broker.
+        if reader.boolean("client-id-suffix").unwrap_or(false) {
+            id = format!("{}-{}", id, utils::random_string(8));
+        }
+        let program_location = reader.string("program-location")
+            .unwrap_or(format!("/etc/{}/program.scm", PROGRAM_NAME));
 
-        let id = match vm.extract_value("client-id") {
-            Result::Ok(val) => val.try_into().unwrap(),
-            Result::Err(_) => PROGRAM_NAME.into(),
+        // The broker can also be given on the command line instead.
+        let addr = match options.broker {
+            Some(_) => reader.string("broker-addr"),
+            None => reader.required_string("broker-addr"),
         };
+        let port = reader.integer("broker-port", "a port number").unwrap_or(1883);
+        let user = reader.string("broker-user");
+        let password = reader.secret("broker-pass");
 
-        let program_location = match vm.extract_value("program-location") {
-            Result::Ok(val) => val.try_into().unwrap(),
-            Result::Err(_) => format!("/etc/{}/program.scm", PROGRAM_NAME),
+        // Subscribed to right after the program has run, so its hooks are in place.
+        let subscriptions = reader.subscriptions("subscriptions").unwrap_or(vec![]).into_iter()
+            .map(|(topic, qos)| (topic, utils::qos(qos)))
+            .collect();
+        let keep_alive = reader.integer("keep-alive", "a number of seconds").unwrap_or(5);
+        let clean_session = reader.boolean("clean-session").unwrap_or(true);
+        let inflight = reader.integer("inflight", "a number of messages between 1 and 65535").unwrap_or(100);
+        if inflight == 0 {
+            reader.error("inflight", "should be at least 1.");
+        }
+        // Either a single level, or levels for single targets like "info,nrepl=debug".
+        let log_filter = match reader.string("log-level") {
+            Some(level) => logging::Filter::from_str(&level).unwrap_or_else(|e| {
+                reader.error("log-level", &format!("is invalid: {}.", e));
+                logging::Filter::new(LevelFilter::Info)
+            }),
+            None => logging::Filter::new(LevelFilter::Info),
+        };
+        let log_format = match reader.string("log-format") {
+            Some(format) => logging::Format::from_str(&format).unwrap_or_else(|_| {
+                reader.error("log-format", &format!("should be one of text, json or journald, but is \"{}\".", format));
+                logging::Format::detect()
+            }),
+            None => logging::Format::detect(),
         };
+        let data_dir = reader.string("data-dir").unwrap_or(format!("/var/lib/{}", PROGRAM_NAME));
 
-        let addr = vm.extract_value("broker-addr").unwrap().try_into().unwrap();
+        let local_repl = reader.boolean("local-repl").unwrap_or(true);
+        let repl_history_file = reader.string("repl-history-file").or_else(default_history_file);
 
-        let port = match vm.extract_value("broker-port") {
-            Result::Ok(val) => val.try_into().unwrap(),
-            Result::Err(_) => 1883,
+        let nrepl_whitelist = reader.string_list("nrepl").unwrap_or(vec![]);
+        for addr in &nrepl_whitelist {
+            if addr.parse::<IpAddr>().is_err() {
+                reader.error("nrepl", &format!("should only contain IP addresses, but contains \"{}\".", addr));
+            }
+        }
+        let nrepl_enabled = reader.boolean("nrepl-enable").unwrap_or(true);
+        let nrepl_addr = reader.string("nrepl-addr").unwrap_or("127.0.0.1".into());
+        let nrepl_port = reader.integer("nrepl-port", "a port number").unwrap_or(7888);
+        let nrepl_port_file = reader.string("nrepl-port-file");
+        let nrepl_token = reader.secret("nrepl-token");
+        let nrepl = match nrepl_enabled {
+            true => Some(nrepl::NReplConfiguration::new(nrepl_addr, nrepl_port, nrepl_whitelist, nrepl_port_file, nrepl_token)),
+            false => None,
         };
-
-        let user = match vm.extract_value("broker-user") {
-            Result::Ok(val) => Some(val.try_into().unwrap()),
-            Result::Err(_) => None,
+        // The control socket is only created when a path is configured. Its mode is given as
+        // an octal string like "600".
+        let control_socket_mode = match reader.string("control-socket-mode") {
+            Some(mode) => match u32::from_str_radix(&mode, 8) {
+                Ok(mode) if mode <= 0o777 => mode,
+                _ => {
+                    reader.error("control-socket-mode", &format!("should be an octal file mode like \"600\", but is \"{}\".", mode));
+                    0o600
+                },
+            },
+            None => 0o600,
         };
-        let password = match vm.extract_value("broker-pass") {
-            Result::Ok(val) => Some(val.try_into().unwrap()),
-            Result::Err(_) => None,
+        let control_socket = match reader.boolean_or_string("control-socket") {
+            Some(Some(path)) => Some(nrepl::ControlSocketConfiguration::new(path, control_socket_mode)),
+            _ => None,
         };
 
-        let local_repl = match vm.extract_value("local-repl") {
-            Result::Ok(val) => val.as_bool().unwrap(),
-            Result::Err(_) => true,
-        };
-        let nrepl = match vm.extract_value("nrepl") {
-            Result::Ok(val) => {
-                let list = val.list().unwrap();
-                let mut vector: Vec<String> = vec![];
-                for s in list {
-                    vector.push(s.try_into().unwrap());
-                }
-                vector
-            },
-            Result::Err(_) => vec![],
+        // The metrics endpoint is off unless enabled, and only listens locally by default.
+        let metrics_enabled = reader.boolean("metrics-enable").unwrap_or(false);
+        let metrics_addr = reader.string("metrics-addr").unwrap_or("127.0.0.1".into());
+        let metrics_port = reader.integer("metrics-port", "a port number").unwrap_or(9188);
+        let metrics = match metrics_enabled {
+            true => Some(metrics::MetricsConfiguration::new(metrics_addr, metrics_port)),
+            false => None,
         };
+        let metric_filters = reader.string_list("metrics-topic-filters").unwrap_or(vec![]);
+        // heinzelmann's own topics are only published when a prefix is configured.
+        let status_prefix = reader.string("status-topic-prefix");
+        // The audit log is rotated once it grows past audit-log-max-size bytes, keeping
+        // audit-log-keep old files.
+        let audit_max_size = reader.integer("audit-log-max-size", "a number of bytes").unwrap_or(10_000_000);
+        let audit_keep = reader.integer("audit-log-keep", "a number of files").unwrap_or(5);
+        let audit = reader.string("audit-log")
+            .map(|path| audit::AuditConfiguration::new(path, audit_max_size, audit_keep));
+        // Incoming messages are appended here, to be replayed with `heinzelmann replay`.
+        let record_file = reader.string("record-file");
+        // Handles messages as usual, but only logs what would have been published.
+        let dry_run = reader.boolean("dry-run").unwrap_or(false);
+
+        // Evaluations get a default time budget so a runaway one can't wedge the VM. REPL
+        // evaluations get more time, and can be interrupted before that.
+        let handler_budget = EvalBudget::from_config(&mut reader, "handler", Some(10000));
+        let repl_budget = EvalBudget::from_config(&mut reader, "repl", Some(60000));
+
+        for warning in reader.finish()? {
+            log::warn!("{}", warning);
+        }
 
-        return Configuration::new(id, program_location, addr, port, user, password, local_repl, nrepl);
+        let mut config = Configuration::new(id, program_location, addr.unwrap_or_default(), port, user, password, subscriptions, keep_alive, clean_session, inflight, log_filter, log_format, data_dir, local_repl, repl_history_file, nrepl, control_socket, metrics, metric_filters, status_prefix, audit, record_file, dry_run, handler_budget, repl_budget);
+        config.apply_options(options);
+        return Ok(config);
     }
 
     fn connect(&self) -> (Client, Connection) {
         let mut mqttoptions: MqttOptions = MqttOptions::new(&self.id, &self.addr, self.port);
-        mqttoptions.set_keep_alive(Duration::from_secs(5));
+        mqttoptions.set_keep_alive(Duration::from_secs(self.keep_alive));
+        mqttoptions.set_clean_session(self.clean_session);
+        mqttoptions.set_inflight(self.inflight);
+        if let Some(prefix) = &self.status_prefix {
+            mqttoptions.set_last_will(diagnostics::last_will(prefix));
+        }
         if let Some(user) = &self.user {
             if let Some(password) = &self.password {
                 mqttoptions.set_credentials(user, password);
@@ -127,39 +247,394 @@ impl Configuration {
         return (client, connection);
     }
 
-    fn get_program(&self) -> String {
+    // Settings from the command line win over the config file.
+    fn apply_options(&mut self, options: &cli::Options) {
+        if let Some((addr, port)) = &options.broker {
+            self.addr = addr.clone();
+            if let Some(port) = port {
+                self.port = *port;
+            }
+        }
+        if let Some(program) = &options.program {
+            self.program_location = program.clone();
+        }
+        if options.no_repl {
+            self.local_repl = false;
+        }
+        if let Some(record_file) = &options.record {
+            self.record_file = Some(record_file.clone());
+        }
+        // A dry run must not touch the status topics of the real instance either, not even
+        // through the last will, and connects with its own client id so the broker doesn't
+        // disconnect the real instance.
+        if options.dry_run {
+            self.dry_run = true;
+        }
+        if self.dry_run {
+            self.status_prefix = None;
+            self.id = format!("{}-{}", self.id, utils::random_string(8));
+        }
+        if let Some(log_filter) = &options.log_filter {
+            self.log_filter = log_filter.clone();
+        }
+    }
+
+    fn get_program(&self) -> Result<String, String> {
         return get_file_contents(&self.program_location);
     }
 }
 
-fn get_file_contents(location: &str) -> String {
-    let contents = fs::read_to_string(location)
-        .expect(&format!("Unable to read file at {}.", location));
-    return contents;
+fn get_file_contents(location: &str) -> Result<String, String> {
+    return fs::read_to_string(location)
+        .map_err(|e| format!("Unable to read file at {}: {}", location, e));
+}
+
+fn default_history_file() -> Option<String> {
+    return env::var("HOME").ok().map(|home| format!("{}/.{}_history", home, PROGRAM_NAME));
 }
 
 
 pub enum VMMessage {
     Command(ReplCommand),
+    Tap(mpsc::Sender<ReplResponse>),
+    Completions(String, mpsc::Sender<Vec<docs::Completion>>),
+    Lookup(String, mpsc::Sender<Option<docs::SymbolInfo>>),
+    CloneSession(Option<String>, String),
+    CloseSession(String),
+    Meta(MetaCommand, mpsc::Sender<ReplResponse>),
     MqttConnect(Client),
+    // Stands in for the broker when only checking the program.
+    MqttOffline,
+    MqttCapture,
+    // Sent periodically so the VM can show it isn't stuck.
+    Heartbeat,
     TimersReady(mpsc::Sender<TimedEvent>),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandOrigin {
+    Handler,
+    Repl,
+}
+
 pub struct ReplCommand {
     cmd: String,
+    // A function to call with string arguments instead of running cmd. Messages and timer ids are
+    // handed to the hooks this way, so nothing in them is ever read as code.
+    call: Option<(String, Vec<String>)>,
+    origin: CommandOrigin,
+    // The file the code was loaded from, if any.
+    path: Option<String>,
+    session: Option<ReplSession>,
+    // The client's id for the evaluation, which an interrupt has to name.
+    message_id: Option<String>,
+    // Links the command to what caused it in the audit log.
+    correlation: Option<String>,
     response_tx: mpsc::Sender<ReplResponse>,
 }
 
 impl ReplCommand {
-    fn new(cmd: String, response_tx: mpsc::Sender<ReplResponse>) -> ReplCommand {
-        return ReplCommand { cmd, response_tx };
+    fn new(cmd: String, origin: CommandOrigin, response_tx: mpsc::Sender<ReplResponse>) -> ReplCommand {
+        return ReplCommand { cmd, call: None, origin, path: None, session: None, message_id: None, correlation: None, response_tx };
+    }
+
+    fn in_session(mut self, session: ReplSession) -> ReplCommand {
+        self.session = Some(session);
+        return self;
+    }
+
+    fn with_message_id(mut self, message_id: String) -> ReplCommand {
+        self.message_id = Some(message_id);
+        return self;
+    }
+
+    fn with_correlation(mut self, correlation: String) -> ReplCommand {
+        self.correlation = Some(correlation);
+        return self;
     }
 
     fn create(cmd: String) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
+        return ReplCommand::create_with_origin(cmd, CommandOrigin::Repl);
+    }
+
+    fn create_handler(cmd: String) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
+        return ReplCommand::create_with_origin(cmd, CommandOrigin::Handler);
+    }
+
+    fn create_from_file(cmd: String, path: String) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
+        let (mut repl_cmd, resp_rx) = ReplCommand::create_with_origin(cmd, CommandOrigin::Repl);
+        repl_cmd.path = Some(path);
+        return (repl_cmd, resp_rx);
+    }
+
+    fn create_call(function: &str, args: Vec<String>) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
+        return ReplCommand::create_call_with_origin(function, args, CommandOrigin::Repl);
+    }
+
+    fn create_handler_call(function: &str, args: Vec<String>) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
+        return ReplCommand::create_call_with_origin(function, args, CommandOrigin::Handler);
+    }
+
+    // cmd is only set to show what was called, in the audit log and in errors.
+    fn create_call_with_origin(function: &str, args: Vec<String>, origin: CommandOrigin) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
+        let shown = format!("({}{})", function, args.iter().map(|arg| format!(" {:?}", arg)).collect::<String>());
+        let (mut repl_cmd, resp_rx) = ReplCommand::create_with_origin(shown, origin);
+        repl_cmd.call = Some((function.into(), args));
+        return (repl_cmd, resp_rx);
+    }
+
+    fn create_with_origin(cmd: String, origin: CommandOrigin) -> (ReplCommand, mpsc::Receiver<ReplResponse>) {
         let (resp_tx, resp_rx): (mpsc::Sender<ReplResponse>, mpsc::Receiver<ReplResponse>) = mpsc::channel();
-        let repl_cmd = ReplCommand::new(cmd, resp_tx);
+        let repl_cmd = ReplCommand::new(cmd, origin, resp_tx);
         return (repl_cmd, resp_rx);
     }
+
+    // What hooks registered by this command are attributed to, and the name its code runs under.
+    fn source(&self) -> String {
+        match (&self.path, &self.session, self.origin) {
+            (Some(path), _, _) => path.clone(),
+            (None, Some(session), _) => format!("session {}", session.id),
+            (None, None, CommandOrigin::Repl) => "repl".into(),
+            (None, None, CommandOrigin::Handler) => "handler".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplSession {
+    id: String,
+    sandboxed: bool,
+}
+
+impl ReplSession {
+    pub fn new(id: String, sandboxed: bool) -> ReplSession {
+        return ReplSession { id, sandboxed };
+    }
+}
+
+// The results of a REPL session's last evaluations, bound to *1, *2, *3 and *e while the
+// session evaluates code.
+#[derive(Clone, Debug)]
+struct SessionHistory {
+    results: [SteelVal; 3],
+    error: SteelVal,
+}
+
+impl SessionHistory {
+    fn new() -> SessionHistory {
+        return SessionHistory { results: [SteelVal::Void, SteelVal::Void, SteelVal::Void], error: SteelVal::Void };
+    }
+
+    fn bind(&self, vm: &mut Engine) {
+        vm.register_value("*1", self.results[0].clone());
+        vm.register_value("*2", self.results[1].clone());
+        vm.register_value("*3", self.results[2].clone());
+        vm.register_value("*e", self.error.clone());
+    }
+
+    fn push_result(&mut self, value: SteelVal) {
+        self.results.rotate_right(1);
+        self.results[0] = value;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EvalBudget {
+    timeout: Option<Duration>,
+    instructions: Option<usize>,
+}
+
+impl EvalBudget {
+    fn unlimited() -> EvalBudget {
+        return EvalBudget { timeout: None, instructions: None };
+    }
+
+    // Reads "<prefix>-timeout" (in milliseconds) and "<prefix>-instruction-limit" from the
+    // configuration. Setting either to #f disables that limit.
+    fn from_config(reader: &mut ConfigReader, prefix: &str, default_timeout: Option<u64>) -> EvalBudget {
+        let timeout = reader.limit(&format!("{}-timeout", prefix)).unwrap_or(default_timeout);
+        let instructions = reader.limit(&format!("{}-instruction-limit", prefix)).unwrap_or(None);
+        return EvalBudget { timeout: timeout.map(Duration::from_millis), instructions: instructions.map(|n| n as usize) };
+    }
+}
+
+pub type SharedInterrupt = Arc<EvalInterrupt>;
+
+// Lets REPL clients interrupt an evaluation they started, and nothing else. The VM tells it which
+// evaluation runs, by session and message id, so that an interrupt arriving late can't stop the
+// next one.
+pub struct EvalInterrupt {
+    running: Mutex<Option<(String, Option<String>)>>,
+    // Evaluations whose client went away before they started, by session and message id.
+    abandoned: Mutex<HashSet<(String, String)>>,
+    requested: AtomicBool,
+}
+
+impl EvalInterrupt {
+    fn shared() -> SharedInterrupt {
+        return Arc::new(EvalInterrupt { running: Mutex::new(None), abandoned: Mutex::new(HashSet::new()), requested: AtomicBool::new(false) });
+    }
+
+    // Called by the VM whenever an evaluation starts, and with None once it is done.
+    fn running(&self, evaluation: Option<(String, Option<String>)>) {
+        let mut running = self.running.lock().unwrap();
+        let abandoned = match &evaluation {
+            Some((session, Some(id))) => self.abandoned.lock().unwrap().remove(&(session.clone(), id.clone())),
+            _ => false,
+        };
+        *running = evaluation;
+        self.requested.store(abandoned, Ordering::Relaxed);
+    }
+
+    // Interrupts an evaluation whose client is gone, right away if it is running or else as soon
+    // as it starts.
+    pub fn abandon(&self, session: &str, id: &str) {
+        let running = self.running.lock().unwrap();
+        match &*running {
+            Some((s, Some(i))) if s == session && i == id => self.requested.store(true, Ordering::Relaxed),
+            _ => {
+                self.abandoned.lock().unwrap().insert((session.into(), id.into()));
+            },
+        }
+    }
+
+    // Interrupts the evaluation running in session, if its id matches or none is given. Returns
+    // whether there was such an evaluation.
+    pub fn request(&self, session: &str, id: Option<&str>) -> bool {
+        let running = self.running.lock().unwrap();
+        match &*running {
+            Some((s, i)) if s == session && id.map_or(true, |id| i.as_deref() == Some(id)) => {
+                self.requested.store(true, Ordering::Relaxed);
+                return true;
+            },
+            _ => return false,
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        return self.requested.load(Ordering::Relaxed);
+    }
+}
+
+// Keeps track of the evaluation that is currently running in the VM and decides, whenever the
+// VM reports its progress, whether it may continue.
+struct EvalWatchdog {
+    budget: EvalBudget,
+    interruptible: bool,
+    started: Instant,
+    instructions: usize,
+    // The VM's instruction count at its last progress report.
+    last_count: usize,
+    halted: Option<EvalHalt>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum EvalHalt {
+    Interrupted,
+    Exhausted(String),
+}
+
+impl EvalWatchdog {
+    fn new() -> EvalWatchdog {
+        return EvalWatchdog {
+            budget: EvalBudget::unlimited(),
+            interruptible: false,
+            started: Instant::now(),
+            instructions: 0,
+            last_count: 0,
+            halted: None,
+        };
+    }
+
+    fn arm(&mut self, budget: EvalBudget, interruptible: bool) {
+        self.budget = budget;
+        self.interruptible = interruptible;
+        self.started = Instant::now();
+        self.instructions = 0;
+        self.halted = None;
+    }
+
+    fn disarm(&mut self) -> Option<EvalHalt> {
+        self.budget = EvalBudget::unlimited();
+        self.interruptible = false;
+        return self.halted.take();
+    }
+
+    // The VM reports its running instruction count every so many instructions, and doesn't reset
+    // it between evaluations, so what ran since the last report is counted. The first report of
+    // an evaluation may include the end of the previous one.
+    fn check(&mut self, count: usize, interrupt: &EvalInterrupt) -> bool {
+        let executed = match count >= self.last_count {
+            true => count - self.last_count,
+            false => count,
+        };
+        self.last_count = count;
+        if self.halted.is_some() {
+            return false;
+        }
+        self.instructions += executed;
+        if self.interruptible && interrupt.is_requested() {
+            self.halted = Some(EvalHalt::Interrupted);
+        }
+        else if let Some(limit) = self.budget.instructions {
+            if self.instructions > limit {
+                self.halted = Some(EvalHalt::Exhausted(format!("instruction limit of {} exceeded", limit)));
+            }
+        }
+        if self.halted.is_none() {
+            if let Some(timeout) = self.budget.timeout {
+                if self.started.elapsed() > timeout {
+                    self.halted = Some(EvalHalt::Exhausted(format!("timeout of {}ms exceeded", timeout.as_millis())));
+                }
+            }
+        }
+        return self.halted.is_none();
+    }
+}
+
+// Decides where output written by the running program ends up. REPL evaluations get their
+// output sent back to whoever asked, everything else goes to the terminal and to any taps.
+struct OutputSink {
+    current: Option<mpsc::Sender<ReplResponse>>,
+    taps: Vec<mpsc::Sender<ReplResponse>>,
+}
+
+impl OutputSink {
+    fn new() -> OutputSink {
+        return OutputSink { current: None, taps: vec![] };
+    }
+
+    fn write(&mut self, stream: String, text: String) {
+        let response = match stream.as_str() {
+            "err" => ReplResponse::Stderr(text.clone()),
+            _ => ReplResponse::Stdout(text.clone()),
+        };
+        if let Some(tx) = &self.current {
+            let _ = tx.send(response);
+            return;
+        }
+        match response {
+            ReplResponse::Stderr(_) => {
+                eprint!("{}", text);
+                let _ = std::io::stderr().flush();
+            },
+            _ => {
+                print!("{}", text);
+                let _ = stdout().flush();
+            },
+        }
+        self.taps.retain(|tap| tap.send(response.clone()).is_ok());
+    }
+}
+
+// Where hooks registered right now come from, and whether that source is sandboxed. The VM
+// thread sets it before every command; it isn't visible to Scheme, so it can't be rebound.
+thread_local! {
+    static HOOK_SOURCE: RefCell<(String, bool)> = RefCell::new(("prelude".into(), false));
+}
+
+fn set_hook_source(source: String, sandboxed: bool) {
+    HOOK_SOURCE.with(|s| *s.borrow_mut() = (source, sandboxed));
 }
 
 #[derive(Clone, Debug, Steel, PartialEq)]
@@ -172,83 +647,213 @@ enum HooksVariant {
 struct Hooks {
     variant: HooksVariant,
     hooks: HashMap<String, SteelVal>,
+    // Where each hook was registered from, e.g. the program file or the REPL.
+    sources: HashMap<String, String>,
 }
 
 impl Hooks {
     fn new(variant: HooksVariant) -> Hooks {
         let hooks = HashMap::new();
-        return Hooks { variant, hooks };
+        let sources = HashMap::new();
+        return Hooks { variant, hooks, sources };
     }
-    fn add_hook(&mut self, topic: SteelVal, f: SteelVal) -> SteelVal {
-        if let SteelVal::StringV(s) = topic {
-            self.hooks.insert(s.to_string(), f);
-            return true.into();
+    // Sets the hook for topic, recording where it came from. Sandboxed sources may only replace
+    // hooks they registered themselves.
+    fn add_hook(&mut self, topic: SteelVal, f: SteelVal) -> Result<SteelVal, SteelErr> {
+        if let SteelVal::StringV(topic) = topic {
+            let topic = topic.to_string();
+            let (source, sandboxed) = HOOK_SOURCE.with(|s| s.borrow().clone());
+            let owner = self.sources.get(&topic);
+            if sandboxed && self.hooks.contains_key(&topic) && owner != Some(&source) {
+                let owner = owner.map_or("the program".to_string(), |o| o.clone());
+                return Err(SteelErr::new(ErrorKind::Generic, format!("The hook for '{}' belongs to {} and can't be replaced from a sandboxed session.", topic, owner)));
+            }
+            self.sources.insert(topic.clone(), source);
+            self.hooks.insert(topic, f);
+            return Ok(true.into());
         }
         else {
-            return false.into();
+            return Ok(false.into());
+        }
+    }
+    // The key of the hook that handles topic. Tree hooks fall back to the closest parent topic
+    // ending in /#, then to #.
+    fn matching_key(&self, s: &str) -> Option<String> {
+        if self.hooks.contains_key(s) {
+            return Some(s.into());
+        }
+        match self.variant {
+            HooksVariant::Simple => {},
+            HooksVariant::Tree => {
+                let mut topic_parts: Vec<String> = s.split("/").map(|s| String::from(s.to_owned())).collect();
+                if s.starts_with("/") {
+                    topic_parts.remove(0);
+                    topic_parts[0] = "/".to_string() + &topic_parts[0];
+                }
+                while topic_parts.len() > 0 {
+                    topic_parts = topic_parts[0..topic_parts.len()-1].to_vec();
+                    let s = topic_parts.join("/") + "/#";
+                    if self.hooks.contains_key(&s) {
+                        return Some(s);
+                    }
+                }
+                if self.hooks.contains_key("#") {
+                    return Some("#".into());
+                }
+            },
         }
+        return None;
     }
     fn find_hook(&self, topic: SteelVal) -> SteelVal {
         if let SteelVal::StringV(s) = topic {
-            let f = self.hooks.get(&s.to_string());
-            match self.variant {
-                HooksVariant::Simple => {
-                    if let Some(f) = f {
-                        return f.clone();
-                    }
-                },
-                HooksVariant::Tree => {
-                    let mut f = f;
-                    let mut topic_parts: Vec<String> = s.split("/").map(|s| String::from(s.to_owned())).collect();
-                    if s.starts_with("/") {
-                        topic_parts.remove(0);
-                        topic_parts[0] = "/".to_string() + &topic_parts[0];
-                    }
-                    while f == None && topic_parts.len() > 0 {
-                        topic_parts = topic_parts[0..topic_parts.len()-1].to_vec();
-                        let s = topic_parts.join("/") + "/#";
-                        f = self.hooks.get(&s);
-                    }
-                    if let Some(f) = f {
-                        return f.clone();
-                    }
-                    else if let Some(f) = self.hooks.get("#") {
-                        return f.clone();
-                    }
-                },
+            if let Some(f) = self.matching_key(&s).and_then(|key| self.hooks.get(&key)) {
+                return f.clone();
+            }
+        }
+        return false.into();
+    }
+    fn find_hook_key(&self, topic: SteelVal) -> SteelVal {
+        if let SteelVal::StringV(s) = topic {
+            if let Some(key) = self.matching_key(&s) {
+                return SteelVal::StringV(key.into());
             }
         }
         return false.into();
     }
+    // One line per hook with the topic (or timer id) and where it was registered from.
+    fn listing(&self) -> String {
+        let mut topics: Vec<&String> = self.hooks.keys().collect();
+        topics.sort();
+        return topics.iter()
+            .map(|topic| {
+                let source = self.sources.get(*topic).map_or("prelude", |s| s.as_str());
+                format!("{} ({})\n", topic, source)
+            })
+            .collect();
+    }
+}
+
+// Asks the VM to show it's alive, often enough for the systemd watchdog if there is one.
+fn heartbeat_thread(tx: mpsc::Sender<VMMessage>) {
+    let interval = match systemd::watchdog_interval() {
+        Some(watchdog) => (watchdog / 2).min(HEARTBEAT_INTERVAL),
+        None => HEARTBEAT_INTERVAL,
+    };
+    while tx.send(VMMessage::Heartbeat).is_ok() {
+        thread::sleep(interval);
+    }
 }
 
-fn timer_thread(repl_tx: mpsc::Sender<VMMessage>) {
+fn timer_thread(repl_tx: mpsc::Sender<VMMessage>, status: SharedStatus, audit: SharedAudit) {
     let timer_guy = timer::Timer::new();
-    let mut guards = vec![];
+    // Dropping a guard cancels its schedule, so replacing a timer's guard moves the timer.
+    let mut guards = HashMap::new();
 
     let (tx, rx): (mpsc::Sender<TimedEvent>, mpsc::Receiver<TimedEvent>) = mpsc::channel();
     repl_tx.send(VMMessage::TimersReady(tx)).unwrap();
 
     for inc in rx {
+        // Setting the same timer again, e.g. when the program is reloaded, doesn't schedule it twice.
+        if !status.lock().unwrap().record_timer(&inc) {
+            continue;
+        }
+        let id = inc.id.clone();
         let rtx = repl_tx.clone();
+        let timer_status = status.clone();
+        let timer_audit = audit.clone();
         let _guard = timer_guy.schedule(
                 inc.get_next_time(), 
                 Some(chrono::Duration::days(1)), 
                 move || {
-                    let cmd = format!(r#"(handle-timer "{}")"#, inc.id);
-                    let (replcmd, rx) = ReplCommand::create(cmd);
+                    let correlation = Audit::new_correlation();
+                    timer_audit.lock().unwrap().record(Some(&correlation), "timer", &[("id", &inc.id)]);
+                    let (replcmd, rx) = ReplCommand::create_handler_call("handle-timer", vec![inc.id.clone()]);
+                    let replcmd = replcmd.with_correlation(correlation);
+                    {
+                        let mut status = timer_status.lock().unwrap();
+                        status.timers_fired += 1;
+                        status.pending_handlers += 1;
+                    }
                     rtx.send(VMMessage::Command(replcmd)).unwrap();
                     rx.recv().unwrap();
                 });
-        guards.push(_guard);
+        guards.insert(id, _guard);
     }
 }
 
-fn vm_thread(rx: mpsc::Receiver<VMMessage>, program: String) {
+// The registered event and timer hooks, as shown by ,hooks.
+fn hooks_listing(vm: &mut Engine) -> String {
+    let mut text = String::new();
+    for (title, hooks) in [("Event hooks", "event-hooks"), ("Timer hooks", "timer-hooks")] {
+        let listing = match vm.extract_value(hooks).and_then(|h| vm.call_function_by_name_with_args("hook-listing", vec![h])) {
+            Ok(SteelVal::StringV(s)) => s.to_string(),
+            _ => String::new(),
+        };
+        text.push_str(&format!("{}:\n{}", title, listing));
+    }
+    return text;
+}
+
+// The names of everything defined in the VM.
+fn global_names(vm: &Engine) -> Vec<String> {
+    return vm.globals().iter().map(|name| name.resolve().to_string()).collect();
+}
+
+// Whether path is the program file, however it was written.
+fn is_program_file(path: &str, program_location: &str) -> bool {
+    if path == program_location {
+        return true;
+    }
+    return match (fs::canonicalize(path), fs::canonicalize(program_location)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+}
+
+// The current values of the protected globals, and whether each compares equal to itself.
+fn protected_values(vm: &Engine, names: &[String]) -> Vec<(String, SteelVal, bool)> {
+    return names.iter()
+        .filter_map(|name| {
+            let value = vm.extract_value(name).ok()?;
+            let comparable = vm.extract_value(name).ok().as_ref() == Some(&value);
+            return Some((name.clone(), value, comparable));
+        })
+        .collect();
+}
+
+// Puts back whatever a sandboxed evaluation redefined and returns the names it redefined.
+// Values that can't be compared, like the hooks, are put back either way.
+fn restore_protected(vm: &mut Engine, protected: Vec<(String, SteelVal, bool)>) -> Vec<String> {
+    let mut redefined = vec![];
+    for (name, value, comparable) in protected {
+        if comparable && vm.extract_value(&name).ok().as_ref() == Some(&value) {
+            continue;
+        }
+        if comparable {
+            redefined.push(name.clone());
+        }
+        vm.register_value(&name, value);
+    }
+    return redefined;
+}
+
+fn vm_thread(rx: mpsc::Receiver<VMMessage>, program: String, program_location: String, subscriptions: Vec<(String, QoS)>, data_dir: String, status_prefix: Option<String>, dry_run: bool, handler_budget: EvalBudget, repl_budget: EvalBudget, interrupt: SharedInterrupt, status: SharedStatus, audit: SharedAudit) {
     let mut vm = Engine::new();
 
+    // SETTING UP EVALUATION LIMITS
+
+    // The VM regularly reports its progress to the watchdog, which aborts the evaluation once it
+    // runs out of budget or a REPL client asks for an interrupt.
+    let watchdog = Arc::new(Mutex::new(EvalWatchdog::new()));
+    let progress_watchdog = watchdog.clone();
+    let progress_interrupt = interrupt.clone();
+    vm.on_progress(move |count| progress_watchdog.lock().unwrap().check(count, &progress_interrupt));
+
     // REGISTERING BASIC UTILITY FUNCTIONS
 
+    // Where the program can keep files that should survive a restart.
+    vm.register_value("data-dir", SteelVal::StringV(data_dir.into()));
+
     // Generates a random string of a specified length. Useful for message identifiers like those
     // that the Meross plugs use.
     vm.register_fn("random-string", utils::random_string);
@@ -259,20 +864,93 @@ fn vm_thread(rx: mpsc::Receiver<VMMessage>, program: String) {
     // other systems as well.
     vm.register_fn("md5", utils::get_md5);
 
+    // SETTING UP OUTPUT CAPTURE
+
+    // The printing functions are replaced so that output can be sent to REPL clients instead of
+    // only ending up on the terminal. Output to an explicit port still goes to that port, through
+    // the original primitive.
+    let output = Arc::new(Mutex::new(OutputSink::new()));
+    let write_output = output.clone();
+    vm.register_fn("write-output", move |stream: String, text: String| write_output.lock().unwrap().write(stream, text));
+    vm.run(r#"
+            (define (output->string x)
+              (if (string? x) x (to-string x)))
+            (define (port-argument? args)
+              (and (not (null? args)) (output-port? (list-ref args (- (length args) 1)))))
+            (define display
+              (let ((port-display display))
+                (lambda args
+                  (if (port-argument? args)
+                    (apply port-display args)
+                    (write-output "out" (apply string-append (map output->string args)))))))
+            (define displayln
+              (let ((port-displayln displayln))
+                (lambda args
+                  (if (port-argument? args)
+                    (apply port-displayln args)
+                    (write-output "out" (string-append (apply string-append (map output->string args)) "\n"))))))
+            (define newline
+              (let ((port-newline newline))
+                (lambda args
+                  (if (port-argument? args)
+                    (apply port-newline args)
+                    (write-output "out" "\n")))))
+            (define eprint
+              (let ((port-eprint eprint))
+                (lambda args
+                  (if (port-argument? args)
+                    (apply port-eprint args)
+                    (write-output "err" (apply string-append (map output->string args)))))))
+            (define eprintln
+              (let ((port-eprintln eprintln))
+                (lambda args
+                  (if (port-argument? args)
+                    (apply port-eprintln args)
+                    (write-output "err" (string-append (apply string-append (map output->string args)) "\n"))))))
+           "#).unwrap();
+
+    // SETTING UP METRICS
+
+    // Counters and gauges of the program's own, served along with heinzelmann's metrics.
+    vm.register_fn("counter-add!", metrics::counter_add_closure(status.clone()));
+    vm.register_fn("gauge-set!", metrics::gauge_set_closure(status.clone()));
+    vm.run(r#"
+            (define (counter-inc! name)
+              (counter-add! name 1))
+           "#).unwrap();
+
+    // SETTING UP LOGGING
+
+    // The program logs through the same logger as heinzelmann itself, under the "program" target.
+    vm.register_fn("write-log", |level: String, message: String| logging::log_program(&level, &message));
+    vm.run(r#"
+            (define (log-debug . args)
+              (write-log "debug" (apply string-append (map output->string args))))
+            (define (log-info . args)
+              (write-log "info" (apply string-append (map output->string args))))
+            (define (log-warn . args)
+              (write-log "warn" (apply string-append (map output->string args))))
+            (define (log-error . args)
+              (write-log "error" (apply string-append (map output->string args))))
+           "#).unwrap();
+
     // SETTING UP HOOKS IMPLEMENTATION
     
     let f: SteelVal = vm.run(r#"
         (lambda (topic msg)
-          (displayln (string-append "Got message '" msg "' on topic '" topic "'.")))
+          (log-debug "Got message '" msg "' on topic '" topic "'."))
         "#).unwrap().last().unwrap().clone();
 
     vm.register_type::<Hooks>("Hooks?");
     vm.register_fn("add-hook!", Hooks::add_hook);
     vm.register_fn("find-hook", Hooks::find_hook);
+    vm.register_fn("find-hook-key", Hooks::find_hook_key);
+    vm.register_fn("audit-hook", audit::audit_hook_closure(audit.clone()));
+    vm.register_fn("hook-listing", Hooks::listing);
 
     // SETTING UP EVENT HOOKS
     let mut event_hooks = Hooks::new(HooksVariant::Tree);
-    event_hooks.add_hook(SteelVal::StringV("#".into()), f);
+    event_hooks.add_hook(SteelVal::StringV("#".into()), f).unwrap();
     vm.register_external_value("event-hooks", event_hooks).unwrap();
 
     // SETTING UP TIMER HOOKS
@@ -281,24 +959,190 @@ fn vm_thread(rx: mpsc::Receiver<VMMessage>, program: String) {
 
     vm.run(r#"
             (define (handle-event topic msg) 
+              (audit-hook "event" topic (find-hook-key event-hooks topic))
               ((find-hook event-hooks topic) topic msg))
             (define (handle-timer id)
+              (audit-hook "timer" id (find-hook-key timer-hooks id))
               ((find-hook timer-hooks id)))
 
             (define (register-event! topic f) 
               (add-hook! event-hooks topic f))
+            (define (register-json-event! topic f)
+              (add-hook! event-hooks topic
+                (lambda (topic msg) (f topic (string->jsexpr msg)))))
             (define (register-timer! id f) 
               (add-hook! timer-hooks id f))
            "#).unwrap();
 
+    // SETTING UP JSON HELPERS
+
+    // Walks a parsed JSON value along a path of keys (symbols or strings) and list indices,
+    // returning #f as soon as a step doesn't exist.
+    vm.run(r#"
+            (define (json-ref-path value path)
+              (cond ((null? path) value)
+                    ((hash? value)
+                     (let ((key (if (string? (car path)) (string->symbol (car path)) (car path))))
+                       (if (hash-contains? value key)
+                         (json-ref-path (hash-get value key) (cdr path))
+                         #f)))
+                    ((and (list? value) (integer? (car path)) (>= (car path) 0) (< (car path) (length value)))
+                     (json-ref-path (list-ref value (car path)) (cdr path)))
+                    (else #f)))
+            (define (json-ref value . path)
+              (json-ref-path value path))
+           "#).unwrap();
+
+    // Sandboxed sessions may not redefine what the program relies on: the prelude so far, and
+    // once the program has run, everything it defined too.
+    let mut protected_globals = global_names(&vm);
+
+    // SETTING UP REPL SESSIONS
+
+    // Each session gets its own *1, *2, *3 and *e. Evaluations from the local REPL share the
+    // "repl" history.
+    let mut histories: HashMap<String, SessionHistory> = HashMap::new();
+    SessionHistory::new().bind(&mut vm);
+
+    // Kept around so the REPL can watch topics the program didn't subscribe to.
+    let mut mqtt_client: Option<Client> = None;
+    let mut status_topics: Option<diagnostics::StatusTopics> = None;
+
     // RUNNING PROGRAM
     let mut pre_flight_checks_mqtt = false;
     let mut pre_flight_checks_timers = false;
     let mut program_run = false;
     for inc in rx {
+        // Reloading runs the program file again as if it had been loaded from the REPL.
+        let inc = match inc {
+            VMMessage::Meta(MetaCommand::Reload, tx) => match fs::read_to_string(&program_location) {
+                Ok(program) => {
+                    let (mut cmd, _) = ReplCommand::create_from_file(program, program_location.clone());
+                    cmd.response_tx = tx;
+                    VMMessage::Command(cmd)
+                },
+                Err(e) => {
+                    let error = ReplError::new("IOError".into(), format!("Unable to read {}: {}", program_location, e), None);
+                    let _ = tx.send(ReplResponse::Error(error));
+                    continue;
+                },
+            },
+            // Injected messages run through the event hooks like real ones, but answer to the REPL.
+            VMMessage::Meta(MetaCommand::Inject(topic, payload), tx) => {
+                let (mut cmd, _) = ReplCommand::create_call("handle-event", vec![topic, payload]);
+                cmd.response_tx = tx;
+                VMMessage::Command(cmd)
+            },
+            inc => inc,
+        };
         match inc {
             VMMessage::Command(cmd) => {
-                let result = vm.compile_and_run_raw_program(&cmd.cmd);
+                let budget = match cmd.origin {
+                    CommandOrigin::Handler => handler_budget,
+                    CommandOrigin::Repl => repl_budget,
+                };
+                if cmd.origin == CommandOrigin::Handler {
+                    let mut status = status.lock().unwrap();
+                    status.pending_handlers = status.pending_handlers.saturating_sub(1);
+                }
+                if cmd.origin == CommandOrigin::Repl {
+                    output.lock().unwrap().current = Some(cmd.response_tx.clone());
+                }
+                let sandboxed = cmd.session.as_ref().map_or(false, |s| s.sandboxed);
+                set_hook_source(cmd.source(), sandboxed);
+                let protected = match sandboxed {
+                    true => protected_values(&vm, &protected_globals),
+                    false => vec![],
+                };
+                let history_key = match &cmd.session {
+                    Some(session) => session.id.clone(),
+                    None => "repl".into(),
+                };
+                // The local REPL interrupts its evaluations as session "repl".
+                interrupt.running(match cmd.origin {
+                    CommandOrigin::Repl => Some((history_key.clone(), cmd.message_id.clone())),
+                    CommandOrigin::Handler => None,
+                });
+                watchdog.lock().unwrap().arm(budget, cmd.origin == CommandOrigin::Repl);
+                if cmd.origin == CommandOrigin::Repl {
+                    histories.entry(history_key.clone()).or_insert_with(SessionHistory::new).bind(&mut vm);
+                }
+                // REPL evaluations are causes of their own.
+                let correlation = match (&cmd.correlation, cmd.origin) {
+                    (Some(correlation), _) => Some(correlation.clone()),
+                    (None, CommandOrigin::Repl) => {
+                        let correlation = Audit::new_correlation();
+                        audit.lock().unwrap().record(Some(&correlation), "eval", &[("source", &cmd.source()), ("code", &cmd.cmd)]);
+                        Some(correlation)
+                    },
+                    (None, CommandOrigin::Handler) => None,
+                };
+                audit.lock().unwrap().set_current(correlation);
+                let started = Instant::now();
+                // Code without a file is run under its source's name, so that its errors can be told
+                // apart from errors in the code it calls.
+                let mut result = match &cmd.call {
+                    Some((function, args)) => {
+                        let args = args.iter().map(|arg| SteelVal::StringV(arg.as_str().into())).collect();
+                        vm.call_function_by_name_with_args(function, args).map(|value| vec![value])
+                    },
+                    None => vm.compile_and_run_raw_program_with_path(&cmd.cmd, PathBuf::from(cmd.source())),
+                };
+                let redefined = restore_protected(&mut vm, protected);
+                // Reloading the program, however it was asked for, may define more.
+                if let (Some(path), false) = (&cmd.path, sandboxed) {
+                    if is_program_file(path, &program_location) {
+                        protected_globals = global_names(&vm);
+                    }
+                }
+                if !redefined.is_empty() {
+                    result = Err(SteelErr::new(ErrorKind::Generic, format!("Sandboxed sessions can't redefine {}.", redefined.join(", "))));
+                }
+                output.lock().unwrap().current = None;
+                let halted = watchdog.lock().unwrap().disarm();
+                interrupt.running(None);
+                {
+                    let mut audit = audit.lock().unwrap();
+                    if let Err(e) = &result {
+                        let error = match &halted {
+                            Some(EvalHalt::Exhausted(reason)) => format!("Evaluation aborted: {}", reason),
+                            Some(EvalHalt::Interrupted) => "Interrupted".into(),
+                            None => e.to_string(),
+                        };
+                        audit.record_current("error", &[("error", &error)]);
+                    }
+                    audit.set_current(None);
+                }
+                if cmd.origin == CommandOrigin::Handler {
+                    let mut status = status.lock().unwrap();
+                    status.handler_runs += 1;
+                    status.handler_latency.observe(started.elapsed());
+                    match (&result, &halted) {
+                        (Err(_), Some(_)) => status.handler_aborts += 1,
+                        (Err(_), None) => status.handler_errors += 1,
+                        _ => {},
+                    }
+                    if let (Some(topics), Err(e)) = (&status_topics, &result) {
+                        let error = match &halted {
+                            Some(EvalHalt::Exhausted(reason)) => format!("Evaluation aborted: {}", reason),
+                            _ => e.to_string(),
+                        };
+                        topics.handler_error(&cmd.cmd, &error);
+                    }
+                }
+                // Reloading shows up as a new program hash.
+                if let (Some(topics), Some(path), Ok(_)) = (&mut status_topics, &cmd.path, &result) {
+                    if *path == program_location {
+                        topics.program_loaded(&cmd.cmd);
+                    }
+                }
+                if cmd.origin == CommandOrigin::Repl {
+                    let history = histories.entry(history_key).or_insert_with(SessionHistory::new);
+                    match &result {
+                        Ok(r) => history.push_result(r.last().cloned().unwrap_or(SteelVal::Void)),
+                        Err(e) => history.error = SteelVal::StringV(e.to_string().into()),
+                    }
+                }
                 match result {
                     Ok(r) => match r.last() {
                         Some(v) => match v {
@@ -312,101 +1156,493 @@ fn vm_thread(rx: mpsc::Receiver<VMMessage>, program: String) {
                         },
                         None => cmd.response_tx.send(ReplResponse::Empty).unwrap(),
                     },
-                    Err(e) => {
-                        vm.raise_error(e.clone());
-                        cmd.response_tx.send(ReplResponse::Error(e.to_string())).unwrap();
+                    Err(e) => match halted {
+                        Some(EvalHalt::Interrupted) => cmd.response_tx.send(ReplResponse::Interrupted).unwrap(),
+                        Some(EvalHalt::Exhausted(reason)) => {
+                            log::warn!("Evaluation of '{}' aborted: {}.", cmd.cmd, reason);
+                            let error = ReplError::new("EvaluationAborted".into(), format!("Evaluation aborted: {}", reason), None);
+                            cmd.response_tx.send(ReplResponse::Error(error)).unwrap();
+                        },
+                        None => {
+                            let error = ReplError::from_steel_error(&e, &cmd.cmd, vm.get_source_id(&PathBuf::from(cmd.source())));
+                            // REPL clients are shown the error themselves, handlers' errors would
+                            // otherwise go unnoticed.
+                            if cmd.origin == CommandOrigin::Handler {
+                                log::error!("{}", error);
+                            }
+                            cmd.response_tx.send(ReplResponse::Error(error)).unwrap();
+                        },
                     },
                 };
             },
+            VMMessage::Tap(tx) => output.lock().unwrap().taps.push(tx),
+            VMMessage::CloneSession(parent, session) => {
+                let history = parent.and_then(|p| histories.get(&p).cloned()).unwrap_or_else(SessionHistory::new);
+                histories.insert(session, history);
+                let mut status = status.lock().unwrap();
+                status.nrepl_sessions += 1;
+                status.nrepl_sessions_created += 1;
+            },
+            VMMessage::CloseSession(session) => {
+                if histories.remove(&session).is_some() {
+                    let mut status = status.lock().unwrap();
+                    status.nrepl_sessions = status.nrepl_sessions.saturating_sub(1);
+                }
+            },
+            VMMessage::Completions(prefix, tx) => {
+                let _ = tx.send(docs::completions(&vm, &prefix));
+            },
+            VMMessage::Lookup(symbol, tx) => {
+                let _ = tx.send(docs::lookup(&vm, &symbol));
+            },
+            VMMessage::Meta(command, tx) => {
+                let text = match command {
+                    MetaCommand::Hooks => hooks_listing(&mut vm),
+                    MetaCommand::Subs => status.lock().unwrap().subscriptions_listing(),
+                    MetaCommand::Timers => status.lock().unwrap().timers_listing(),
+                    MetaCommand::Last(topic) => status.lock().unwrap().last_message_listing(&topic),
+                    MetaCommand::Stats => status.lock().unwrap().stats_listing(),
+                    // Matching messages keep being sent to tx until the filter is unwatched.
+                    MetaCommand::Watch(filter) => match &mqtt_client {
+                        Some(client) => {
+                            let mut status = status.lock().unwrap();
+                            let overlapping = status.overlaps_subscription(&filter);
+                            if !overlapping && !status.is_watched(&filter) {
+                                if let Err(e) = client.clone().subscribe(filter.clone(), QoS::AtMostOnce) {
+                                    let _ = tx.send(ReplResponse::Stderr(format!("Unable to subscribe to '{}': {}\n", filter, e)));
+                                    continue;
+                                }
+                            }
+                            status.add_watch(filter.clone(), tx.clone());
+                            let text = match overlapping {
+                                true => format!("Watching '{}', which overlaps the program's subscriptions, so only messages the program receives are shown.\n", filter),
+                                false => format!("Watching '{}'.\n", filter),
+                            };
+                            let _ = tx.send(ReplResponse::Stdout(text));
+                            continue;
+                        },
+                        None => "Not connected to the broker yet.\n".into(),
+                    },
+                    MetaCommand::Unwatch(filter) => {
+                        let mut status = status.lock().unwrap();
+                        let removed = status.remove_watches(filter.as_deref());
+                        for filter in &removed {
+                            if let Some(client) = &mqtt_client {
+                                if !status.overlaps_subscription(filter) {
+                                    let _ = client.clone().unsubscribe(filter.clone());
+                                }
+                            }
+                        }
+                        match removed.is_empty() {
+                            true => "Nothing was being watched.\n".into(),
+                            false => format!("Stopped watching {}.\n", removed.join(", ")),
+                        }
+                    },
+                    MetaCommand::Reload | MetaCommand::Inject(_, _) => unreachable!(),
+                };
+                let _ = tx.send(ReplResponse::Stdout(text));
+                let _ = tx.send(ReplResponse::Empty);
+            },
             VMMessage::MqttConnect(c) => {
-                vm.register_fn("send-simple", utils::send_closure(c.clone(), false));
-                vm.register_fn("send-retain", utils::send_closure(c.clone(), true));
-                vm.register_fn("subscribe", utils::subscribe_closure(c));
+                // In a dry run, subscriptions and handlers work as usual, but nothing is published.
+                match dry_run {
+                    true => {
+                        vm.register_fn("send-simple", utils::dry_send_closure(false, audit.clone()));
+                        vm.register_fn("send-retain", utils::dry_send_closure(true, audit.clone()));
+                    },
+                    false => {
+                        vm.register_fn("send-simple", utils::send_closure(c.clone(), false, status.clone(), audit.clone()));
+                        vm.register_fn("send-retain", utils::send_closure(c.clone(), true, status.clone(), audit.clone()));
+                    },
+                }
+                vm.register_fn("subscribe", utils::subscribe_closure(c.clone(), status.clone()));
+                status_topics = status_prefix.clone().map(|prefix| diagnostics::StatusTopics::new(prefix, c.clone()));
+                mqtt_client = Some(c);
+                pre_flight_checks_mqtt = true;
+            },
+            // Replays show what would have been published instead of sending it.
+            VMMessage::MqttCapture => {
+                vm.register_fn("send-simple", |topic: String, payload: String| println!("{} publish {} {}", utils::now().format("%Y-%m-%d %H:%M:%S"), topic, payload));
+                vm.register_fn("send-retain", |topic: String, payload: String| println!("{} publish (retained) {} {}", utils::now().format("%Y-%m-%d %H:%M:%S"), topic, payload));
+                vm.register_fn("subscribe", |_: String| ());
+                pre_flight_checks_mqtt = true;
+            },
+            VMMessage::MqttOffline => {
+                vm.register_fn("send-simple", |_: String, _: String| ());
+                vm.register_fn("send-retain", |_: String, _: String| ());
+                vm.register_fn("subscribe", |_: String| ());
                 pre_flight_checks_mqtt = true;
             },
             VMMessage::TimersReady(tx) => {
                 vm.register_fn("set-timer", utils::set_timer_closure(tx));
                 pre_flight_checks_timers = true;
             },
+            // A handler that never returns keeps these from being answered, which lets systemd
+            // restart heinzelmann.
+            VMMessage::Heartbeat => {
+                status.lock().unwrap().vm_heartbeat = Instant::now();
+                systemd::notify("WATCHDOG=1");
+                if let Some(topics) = &mut status_topics {
+                    topics.refresh(hooks_listing(&mut vm), &status.lock().unwrap());
+                }
+            },
         }
         if !program_run && pre_flight_checks_mqtt && pre_flight_checks_timers {
-            vm.compile_and_run_raw_program(&program).unwrap(); //TODO: Signify when the service fails because the provided program fails
+            set_hook_source(program_location.clone(), false);
+            if let Err(e) = vm.compile_and_run_raw_program_with_path(&program, PathBuf::from(&program_location)) {
+                log::error!("The program at {} failed: {}", program_location, ReplError::from_steel_error(&e, &program, vm.get_source_id(&PathBuf::from(&program_location))));
+                process::exit(1);
+            }
             program_run = true;
+            protected_globals = global_names(&vm);
+            status.lock().unwrap().program_loaded = true;
+            if let Some(topics) = &mut status_topics {
+                topics.program_loaded(&program);
+            }
+            systemd::notify_ready(&status);
+            if let Some(client) = &mqtt_client {
+                for (topic, qos) in &subscriptions {
+                    status.lock().unwrap().record_subscription(topic);
+                    if let Err(e) = client.clone().subscribe(topic.clone(), *qos) {
+                        log::error!("Unable to subscribe to '{}': {}", topic, e);
+                    }
+                }
+            }
         }
     }
 }
 
-enum ReplResponse {
+#[derive(Clone, Debug)]
+pub enum ReplResponse {
+    Stdout(String),
+    Stderr(String),
     Empty,
     Return(String),
-    Error(String),
+    Error(ReplError),
+    Interrupted,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplError {
+    kind: String,
+    message: String,
+    // Line and column (both starting at 1) in the evaluated code where the error occurred.
+    location: Option<(usize, usize)>,
 }
 
-fn repl_thread(tx: mpsc::Sender<VMMessage>) {
-    let stdin = std::io::stdin();
-    let mut buf = String::new();
+impl ReplError {
+    fn new(kind: String, message: String, location: Option<(usize, usize)>) -> ReplError {
+        return ReplError { kind, message, location };
+    }
 
-    loop {
-        print!(">>> ");
-        stdout().flush().unwrap();
-        stdin.read_line(&mut buf).unwrap();
-        let tx_line = buf.clone()
-            .strip_suffix("\n").unwrap()
-            .to_string();
-        if tx_line.starts_with("(quit)") {
-            break;
-        }
-        let (repl_cmd, resp_rx) = ReplCommand::create(tx_line);
-        tx.send(VMMessage::Command(repl_cmd)).unwrap();
-        buf = "".into();
-        match resp_rx.recv().unwrap() {
-            ReplResponse::Empty => println!("=> ()"),
-            ReplResponse::Return(s) => println!("=> {}", s),
-            ReplResponse::Error(_) => {},
+    // Errors are only given a location when they lie in code itself, which came from source.
+    // Errors in code it called, like the prelude or another file, would point to the wrong place.
+    fn from_steel_error(e: &SteelErr, code: &str, source: Option<SourceId>) -> ReplError {
+        let location = e.span()
+            .filter(|span| source.is_some() && span.source_id() == source)
+            .and_then(|span| utils::line_and_column(code, span.start));
+        return ReplError::new(format!("{:?}", e.kind()), e.to_string(), location);
+    }
+
+    pub fn kind(&self) -> &str {
+        return &self.kind;
+    }
+
+    pub fn message(&self) -> &str {
+        return &self.message;
+    }
+
+    pub fn location(&self) -> Option<(usize, usize)> {
+        return self.location;
+    }
+}
+
+impl std::fmt::Display for ReplError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.location {
+            Some((line, column)) => write!(f, "{} (at line {}, column {})", self.message, line, column),
+            None => write!(f, "{}", self.message),
         }
     }
 }
 
 fn main() {
-    println!("Starting {}...", PROGRAM_NAME);
     let args: Vec<String> = env::args().collect();
-    let config_location: String = {
-        if args.len() > 1 {
-            args[1].clone()
-        }
-        else {
-            format!("/etc/{}/config.scm", PROGRAM_NAME).into()
-        }
+    let command = match cli::parse(&args[1..]) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::usage());
+            process::exit(2);
+        },
     };
-    let config_program = get_file_contents(&config_location);
-    let config = Configuration::from_config_program(config_program);
-    let program = config.get_program();
+    match command {
+        cli::Command::Run(options) => run(options),
+        cli::Command::Check(options) => process::exit(check(options)),
+        cli::Command::Replay { options, recording, speed } => process::exit(replay(options, &recording, speed)),
+        cli::Command::Eval { socket, code } => process::exit(ctl::eval(&socket, &code)),
+        cli::Command::Repl { socket } => process::exit(ctl::repl(&socket, default_history_file())),
+        cli::Command::Version => println!("{} {}", PROGRAM_NAME, env!("CARGO_PKG_VERSION")),
+        cli::Command::Help => println!("{}", cli::usage()),
+    }
+}
+
+// Loads the config and the program it points to. If that fails, every problem found is reported
+// and heinzelmann exits.
+fn load_config(options: &cli::Options) -> (Configuration, String) {
+    let loaded = get_file_contents(&options.config_location)
+        .map_err(|e| vec![e])
+        .and_then(|source| Configuration::from_config_program(&options.config_location, source, options))
+        .and_then(|config| match config.get_program() {
+            Ok(program) => Ok((config, program)),
+            Err(e) => Err(vec![e]),
+        });
+    match loaded {
+        Ok(loaded) => {
+            logging::configure(loaded.0.log_filter.clone(), loaded.0.log_format);
+            return loaded;
+        },
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            process::exit(1);
+        },
+    }
+}
 
+fn spawn_vm(config: &Configuration, program: String, interrupt: SharedInterrupt, status: SharedStatus, audit: SharedAudit) -> mpsc::Sender<VMMessage> {
     let (tx, rx): (mpsc::Sender<VMMessage>, mpsc::Receiver<VMMessage>) = mpsc::channel();
-    thread::spawn(move || vm_thread(rx, program));
+    let handler_budget = config.handler_budget;
+    let repl_budget = config.repl_budget;
+    let program_location = config.program_location.clone();
+    let subscriptions = config.subscriptions.clone();
+    let data_dir = config.data_dir.clone();
+    let status_prefix = config.status_prefix.clone();
+    let dry_run = config.dry_run;
+    thread::spawn(move || vm_thread(rx, program, program_location, subscriptions, data_dir, status_prefix, dry_run, handler_budget, repl_budget, interrupt, status, audit));
+    return tx;
+}
+
+// Runs the program without a broker, so mistakes in the config or the program show up before
+// deploying them. Returns the exit code.
+fn check(options: cli::Options) -> i32 {
+    logging::init(options.log_filter.clone().unwrap_or(logging::Filter::new(LevelFilter::Info)), logging::Format::detect());
+    let (config, program) = load_config(&options);
+    let tx = spawn_vm(&config, program, EvalInterrupt::shared(), Status::shared(), Audit::shared(None));
+
+    tx.send(VMMessage::MqttOffline).unwrap();
+    let (timer_tx, _timer_rx) = mpsc::channel();
+    tx.send(VMMessage::TimersReady(timer_tx)).unwrap();
+
+    // The program runs as soon as the VM is ready, so once this is answered it ran fine. If it
+    // didn't, the VM exits on its own.
+    let (cmd, resp_rx) = ReplCommand::create_handler("#t".into());
+    tx.send(VMMessage::Command(cmd)).unwrap();
+    if resp_rx.recv().is_err() {
+        return 1;
+    }
+    println!("{} and {} look fine.", options.config_location, config.program_location);
+    return 0;
+}
+
+// Runs a handler in the VM and waits for it. The VM logs its errors, as it would when running.
+fn run_handler(tx: &mpsc::Sender<VMMessage>, (cmd, resp_rx): (ReplCommand, mpsc::Receiver<ReplResponse>)) {
+    tx.send(VMMessage::Command(cmd)).unwrap();
+    let _ = resp_rx.recv();
+}
+
+// Waits as long as it took to get from one recorded time to the next, sped up by speed. A speed
+// of 0 doesn't wait at all.
+fn wait_between(from: DateTime<Local>, to: DateTime<Local>, speed: f64) {
+    if speed <= 0.0 {
+        return;
+    }
+    if let Ok(delay) = (to - from).to_std() {
+        thread::sleep(delay.div_f64(speed));
+    }
+}
+
+// Feeds a recording through the event hooks on a simulated clock, firing timers as they would
+// have fired, and prints what the program would have published. Returns the exit code.
+fn replay(options: cli::Options, recording: &str, speed: f64) -> i32 {
+    logging::init(options.log_filter.clone().unwrap_or(logging::Filter::new(LevelFilter::Info)), logging::Format::detect());
+    let messages = match recording::read(recording) {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+    let mut clock = match messages.first() {
+        Some(message) => message.time,
+        None => {
+            println!("{} has no messages to replay.", recording);
+            return 0;
+        },
+    };
+    utils::set_simulated_time(clock);
+
+    let (config, program) = load_config(&options);
+    let tx = spawn_vm(&config, program, EvalInterrupt::shared(), Status::shared(), Audit::shared(None));
+    tx.send(VMMessage::MqttCapture).unwrap();
+    let (timer_tx, timer_rx) = mpsc::channel();
+    tx.send(VMMessage::TimersReady(timer_tx)).unwrap();
+
+    // Once this has run, so has the program, and the timers it set are known.
+    run_handler(&tx, ReplCommand::create_handler("#t".into()));
+
+    let mut timers = recording::TimerSchedule::new();
+    for message in messages {
+        // Timers set so far, including by the last handler, fire on the way to this message.
+        loop {
+            for timer in timer_rx.try_iter() {
+                timers.add(timer, clock);
+            }
+            let (time, id) = match timers.next_due(message.time) {
+                Some(due) => due,
+                None => break,
+            };
+            wait_between(clock, time, speed);
+            clock = time;
+            utils::set_simulated_time(clock);
+            println!("{} timer {}", clock.format("%Y-%m-%d %H:%M:%S"), id);
+            run_handler(&tx, ReplCommand::create_handler_call("handle-timer", vec![id]));
+        }
+
+        wait_between(clock, message.time, speed);
+        clock = message.time;
+        utils::set_simulated_time(clock);
+        println!("{} message {} {}", clock.format("%Y-%m-%d %H:%M:%S"), message.topic, message.payload);
+        run_handler(&tx, ReplCommand::create_handler_call("handle-event", vec![message.topic, message.payload]));
+    }
+    return 0;
+}
+
+fn run(options: cli::Options) {
+    logging::init(options.log_filter.clone().unwrap_or(logging::Filter::new(LevelFilter::Info)), logging::Format::detect());
+    log::info!("Starting {}...", PROGRAM_NAME);
+    let (config, program) = load_config(&options);
+    if config.dry_run {
+        log::warn!("Dry run: messages are handled, but nothing will be published.");
+    }
+    if let Err(e) = fs::create_dir_all(&config.data_dir) {
+        log::warn!("Unable to create the data directory at {}: {}", config.data_dir, e);
+    }
+
+    let interrupt = EvalInterrupt::shared();
+    let status = Status::shared();
+    status.lock().unwrap().set_metric_filters(config.metric_filters.clone());
+    let audit = Audit::shared(config.audit.clone());
+    let tx = spawn_vm(&config, program, interrupt.clone(), status.clone(), audit.clone());
 
     if config.local_repl {
         let repl_tx = tx.clone();
-        thread::spawn(move || repl_thread(repl_tx));
+        let history_file = config.repl_history_file.clone();
+        let repl_interrupt = interrupt.clone();
+        thread::spawn(move || repl::repl_thread(repl_tx, history_file, repl_interrupt));
+    }
+
+    if let Some(nrepl_config) = config.nrepl.clone() {
+        let nrepl_tx = tx.clone();
+        let nrepl_interrupt = interrupt.clone();
+        thread::spawn(move || nrepl::nrepl_thread(nrepl_tx, nrepl_config, nrepl_interrupt));
     }
 
-    let nrepl_tx = tx.clone();
-    let nrepl_config = config.nrepl.clone();
-    thread::spawn(move || nrepl::nrepl_thread(nrepl_tx, nrepl_config));
+    if let Some(control_config) = config.control_socket.clone() {
+        let control_tx = tx.clone();
+        let control_interrupt = interrupt.clone();
+        thread::spawn(move || nrepl::control_socket_thread(control_tx, control_config, control_interrupt));
+    }
+
+    if let Some(metrics_config) = config.metrics.clone() {
+        let metrics_status = status.clone();
+        thread::spawn(move || metrics::metrics_thread(metrics_config, metrics_status));
+    }
+
+    let heartbeat_tx = tx.clone();
+    thread::spawn(move || heartbeat_thread(heartbeat_tx));
 
     let timer_tx = tx.clone();
-    thread::spawn(move || timer_thread(timer_tx));
+    let timer_status = status.clone();
+    let timer_audit = audit.clone();
+    thread::spawn(move || timer_thread(timer_tx, timer_status, timer_audit));
+
+    let mut recorder = config.record_file.as_ref().and_then(|path| match recording::Recorder::open(path) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            log::error!("Unable to open the recording at {}: {}", path, e);
+            None
+        },
+    });
 
     let (client, mut conn) = config.connect();
+    let status_client = client.clone();
     tx.send(VMMessage::MqttConnect(client)).unwrap();
 
     for (_, notification) in conn.iter().enumerate() {
-        let event = notification.unwrap();
+        // The connection is retried on the next iteration, so errors only have to be reported.
+        let event = match notification {
+            Ok(event) => event,
+            Err(e) => {
+                let was_connected = std::mem::replace(&mut status.lock().unwrap().broker_connected, false);
+                if was_connected {
+                    log::warn!("Lost the connection to the broker: {}", e);
+                } else {
+                    log::debug!("Unable to connect to the broker: {}", e);
+                }
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            },
+        };
         match event {
             Event::Incoming(packet) => match packet {
+                Packet::ConnAck(ack) => {
+                    let filters = {
+                        let mut status = status.lock().unwrap();
+                        status.connections += 1;
+                        status.broker_connected = true;
+                        status.broker_filters()
+                    };
+                    // Without a session on the broker, the subscriptions are gone and have to be
+                    // made again. The first connection has none yet.
+                    if !ack.session_present {
+                        for filter in filters {
+                            if let Err(e) = status_client.try_subscribe(filter.clone(), QoS::AtMostOnce) {
+                                log::warn!("Unable to subscribe to '{}' again: {}", filter, e);
+                            }
+                        }
+                    }
+                    systemd::notify_ready(&status);
+                    if let Some(prefix) = &config.status_prefix {
+                        diagnostics::connected(&status_client, prefix);
+                    }
+                },
                 Packet::Publish(inc) => {
-                    let exp = format!(r#"(handle-event "{}" "{}")"#, inc.topic, str::replace(std::str::from_utf8(&inc.payload).unwrap(), "\"", "\\\""));
-                    let (cmd, rx) = ReplCommand::create(exp.into());
+                    let payload = std::str::from_utf8(&inc.payload).unwrap();
+                    if let Some(prefix) = &config.status_prefix {
+                        if inc.topic == diagnostics::topic(prefix, "command") {
+                            diagnostics::handle_command(payload, &tx, &status_client, prefix);
+                            continue;
+                        }
+                    }
+                    let subscribed = {
+                        let mut status = status.lock().unwrap();
+                        status.record_message(&inc.topic, payload);
+                        status.is_subscribed(&inc.topic)
+                    };
+                    let correlation = Audit::new_correlation();
+                    audit.lock().unwrap().record(Some(&correlation), "message", &[("topic", &inc.topic), ("payload", payload), ("handled", &subscribed.to_string())]);
+                    // Messages that only arrive because the REPL is watching them aren't handled.
+                    if !subscribed {
+                        continue;
+                    }
+                    // Only what the program handles is recorded, since a replay handles it all.
+                    if let Some(recorder) = &mut recorder {
+                        recorder.record(&inc.topic, payload);
+                    }
+                    let (cmd, rx) = ReplCommand::create_handler_call("handle-event", vec![inc.topic.clone(), payload.into()]);
+                    let cmd = cmd.with_correlation(correlation);
+                    status.lock().unwrap().pending_handlers += 1;
                     tx.send(VMMessage::Command(cmd)).unwrap();
                     rx.recv().unwrap();
                 },
@@ -0,0 +1,234 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Serves the counters collected in the status in the Prometheus text format, along with the
+// counters and gauges the program defines itself, and a health report for supervisors.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use steel::{SteelVal, SteelErr};
+use steel::rerrs::ErrorKind;
+use crate::status::SharedStatus;
+
+// Handler latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+#[derive(Clone, Debug)]
+pub struct MetricsConfiguration {
+    addr: String,
+    port: u16,
+}
+
+impl MetricsConfiguration {
+    pub fn new(addr: String, port: u16) -> MetricsConfiguration {
+        return MetricsConfiguration { addr, port };
+    }
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn latency() -> Histogram {
+        return Histogram { buckets: LATENCY_BUCKETS, counts: vec![0; LATENCY_BUCKETS.len()], sum: 0.0, count: 0 };
+    }
+
+    pub fn observe(&mut self, duration: Duration) {
+        let value = duration.as_secs_f64();
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CustomKind {
+    Counter,
+    Gauge,
+}
+
+impl CustomKind {
+    fn name(&self) -> &'static str {
+        match self {
+            CustomKind::Counter => return "counter",
+            CustomKind::Gauge => return "gauge",
+        }
+    }
+}
+
+// Counters and gauges defined by the program, by their name as exposed.
+pub type CustomMetrics = BTreeMap<String, (CustomKind, f64)>;
+
+// Writes the metrics in the Prometheus text format.
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        return Exposition { text: String::new() };
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.text, "{} {}", name, value);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.text, "{} {}", name, value);
+    }
+
+    // A counter with one value per topic filter.
+    pub fn counter_by_filter(&mut self, name: &str, help: &str, values: &BTreeMap<String, u64>) {
+        self.header(name, "counter", help);
+        for (filter, value) in values {
+            let _ = writeln!(self.text, "{}{{filter=\"{}\"}} {}", name, escape_label(filter), value);
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+        for (bucket, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+            let _ = writeln!(self.text, "{}_bucket{{le=\"{}\"}} {}", name, bucket, count);
+        }
+        let _ = writeln!(self.text, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+        let _ = writeln!(self.text, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(self.text, "{}_count {}", name, histogram.count);
+    }
+
+    pub fn custom(&mut self, metrics: &CustomMetrics) {
+        for (name, (kind, value)) in metrics {
+            self.header(name, kind.name(), "Defined by the program.");
+            let _ = writeln!(self.text, "{} {}", name, value);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        return self.text;
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+// The name a program's metric is exposed as. Hyphens, as usual in Scheme, become underscores.
+fn custom_name(name: &str) -> Result<String, SteelErr> {
+    let name = name.replace('-', "_");
+    let valid = name.chars().next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(SteelErr::new(ErrorKind::Generic, format!("'{}' is not a valid metric name.", name)));
+    }
+    return Ok(format!("heinzelmann_program_{}", name));
+}
+
+fn number(value: &SteelVal) -> Result<f64, SteelErr> {
+    match value {
+        SteelVal::IntV(i) => return Ok(*i as f64),
+        SteelVal::NumV(n) => return Ok(*n),
+        _ => return Err(SteelErr::new(ErrorKind::TypeMismatch, format!("Expected a number, got {}.", value))),
+    }
+}
+
+fn update_custom(status: &SharedStatus, name: String, kind: CustomKind, value: SteelVal) -> Result<(), SteelErr> {
+    let name = custom_name(&name)?;
+    let value = number(&value)?;
+    let mut status = status.lock().unwrap();
+    let metric = status.custom_metrics.entry(name.clone()).or_insert((kind, 0.0));
+    if metric.0 != kind {
+        return Err(SteelErr::new(ErrorKind::Generic, format!("{} is already a {}.", name, metric.0.name())));
+    }
+    match kind {
+        CustomKind::Counter if value < 0.0 => return Err(SteelErr::new(ErrorKind::Generic, format!("{} can't be decreased.", name))),
+        CustomKind::Counter => metric.1 += value,
+        CustomKind::Gauge => metric.1 = value,
+    }
+    return Ok(());
+}
+
+// (counter-add! name amount)
+pub fn counter_add_closure(status: SharedStatus) -> impl Fn(String, SteelVal) -> Result<(), SteelErr> {
+    return move |name, amount| update_custom(&status, name, CustomKind::Counter, amount);
+}
+
+// (gauge-set! name value)
+pub fn gauge_set_closure(status: SharedStatus) -> impl Fn(String, SteelVal) -> Result<(), SteelErr> {
+    return move |name, value| update_custom(&status, name, CustomKind::Gauge, value);
+}
+
+pub fn metrics_thread(config: MetricsConfiguration, status: SharedStatus) {
+    let listener = match TcpListener::bind((config.addr.as_str(), config.port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to start metrics server on {}:{}: {}", config.addr, config.port, e);
+            return;
+        },
+    };
+    log::info!("Metrics available at http://{0}:{1}/metrics, health at http://{0}:{1}/healthz.", config.addr, config.port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let stream_status = status.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, stream_status) {
+                log::debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+// Answers a single HTTP request, for GET /metrics or GET /healthz.
+fn serve(mut stream: TcpStream, status: SharedStatus) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers don't matter, but have to be read before answering.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+    let (code, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", status.lock().unwrap().metrics_listing()),
+        ("GET", "/healthz") => {
+            let status = status.lock().unwrap();
+            let code = if status.healthy() { "200 OK" } else { "503 Service Unavailable" };
+            (code, "application/json", status.health_listing())
+        },
+        ("GET", _) => ("404 Not Found", "text/plain", "Not found.\n".into()),
+        _ => ("405 Method Not Allowed", "text/plain", "Only GET is supported.\n".into()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", code, content_type, body.len(), body)?;
+    return stream.flush();
+}
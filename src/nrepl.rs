@@ -1,152 +1,933 @@
-use std::io::{prelude::*, BufReader};
+use std::io::{self, prelude::*, BufReader};
 use std::net::{TcpListener, TcpStream, IpAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::thread;
-use std::sync::mpsc;
-use crate::{VMMessage, ReplCommand, ReplResponse};
-use std::time::Duration;
+use std::fs;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::{VMMessage, ReplCommand, ReplResponse, ReplError, ReplSession, SharedInterrupt, PROGRAM_NAME};
+use crate::utils;
+use crate::docs::{self, SymbolInfo};
 use serde::{Serialize, Deserialize};
 
+// Ops that are actually answered, as advertised by describe.
+const SUPPORTED_OPS: &[&str] = &["clone", "eval", "describe", "ls-sessions", "close", "interrupt", "out-subscribe", "out-unsubscribe", "stacktrace", "completions", "complete", "lookup", "info", "load-file"];
+
+// Refuse to buffer messages or nest values beyond these sizes.
+const MAX_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
+const MAX_NESTING_DEPTH: usize = 64;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "op", rename_all = "kebab-case")]
 enum NReplMessage {
     AddMiddleware,
-    Clone { id: u64, session: Option<u64> },
-    Close { session: u64 },
-    Completions,
-    Describe,
-    Eval { code: String, session: u64, column: Option<u64>, file: Option<String>, id: u64, line: Option<u64> },
-    Interrupt,
-    LoadFile,
-    Lookup,
+    Clone { id: MessageId, session: Option<String>, sandbox: Option<u64> },
+    Close { id: MessageId, session: String },
+    Complete { id: MessageId, session: Option<String>, prefix: String },
+    Completions { id: MessageId, session: Option<String>, prefix: String },
+    Describe { id: Option<MessageId> },
+    Eval { code: String, session: String, column: Option<u64>, file: Option<String>, id: MessageId, line: Option<u64> },
+    Interrupt { id: MessageId, session: String, interrupt_id: Option<MessageId> },
+    LoadFile { id: MessageId, session: String, file: String, file_name: Option<String>, file_path: Option<String> },
+    Info { id: MessageId, session: Option<String>, sym: String },
+    Lookup { id: MessageId, session: Option<String>, sym: String },
     LsMiddleware,
-    LsSessions,
+    LsSessions { id: MessageId },
+    OutSubscribe { id: MessageId, session: String },
+    OutUnsubscribe { id: MessageId, session: String },
+    Stacktrace { id: MessageId, session: String },
     SideloaderProvide,
     SideloaderStart,
     Stdin,
     SwapMiddleware,
 }
 
+// Clients pick the ids of their messages. The spec makes them strings, but some clients send
+// integers, so both are accepted and echoed back unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum MessageId {
+    Text(String),
+    Number(i64),
+}
+
+impl MessageId {
+    // The id as the VM knows it, which doesn't care how the client sent it.
+    fn key(&self) -> String {
+        match self {
+            MessageId::Text(s) => return s.clone(),
+            MessageId::Number(n) => return n.to_string(),
+        }
+    }
+}
+
+// The fields every message should have, used to answer messages that can't be fully decoded.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct MessageHeader {
+    op: Option<String>,
+    id: Option<MessageId>,
+    session: Option<String>,
+}
+
+// Read on its own, so that a message whose other fields can't be decoded still authenticates
+// by its token, or fails to.
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenHeader {
+    token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct ErrorResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<MessageId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    status: Vec<String>,
+}
+
+impl ErrorResponse {
+    fn encode(header: &MessageHeader, status: &[&str]) -> Vec<u8> {
+        let response = ErrorResponse {
+            id: header.id.clone(),
+            session: header.session.clone(),
+            status: status.iter().map(|s| s.to_string()).collect(),
+        };
+        return bt_bencode::to_vec(&response).unwrap();
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 struct CloneResponse {
-    id: u64,
-    new_session: u64,
+    id: MessageId,
+    new_session: String,
     status: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 struct EvalResponse {
-    id: u64,
-    session: u64,
+    id: MessageId,
+    session: String,
     value: String,
     status: Vec<String>,
     ns: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct OutputResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<MessageId>,
+    session: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    out: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<String>,
+}
+
+impl OutputResponse {
+    // Turns output captured in the VM into a message, or returns None for anything else.
+    fn encode(id: Option<MessageId>, session: String, response: &ReplResponse) -> Option<Vec<u8>> {
+        let response = match response {
+            ReplResponse::Stdout(s) => OutputResponse { id, session, out: Some(s.clone()), err: None },
+            ReplResponse::Stderr(s) => OutputResponse { id, session, out: None, err: Some(s.clone()) },
+            _ => return None,
+        };
+        return Some(bt_bencode::to_vec(&response).unwrap());
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct EvalErrorResponse {
+    id: MessageId,
+    session: String,
+    ex: String,
+    root_ex: String,
+    status: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct StackFrame {
+    name: String,
+    file: String,
+    line: u64,
+    column: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct StacktraceResponse {
+    id: MessageId,
+    session: String,
+    class: String,
+    message: String,
+    stacktrace: Vec<StackFrame>,
+    status: Vec<String>,
+}
+
+// Where in the client's files a piece of evaluated code came from.
+struct CodeOrigin {
+    file: Option<String>,
+    line: Option<u64>,
+    column: Option<u64>,
+}
+
+// Sessions are shared by all connections, so a client can pick a session back up after
+// reconnecting.
+struct Session {
+    // Sandboxed sessions can't replace hooks that were registered from anywhere else, nor
+    // redefine what the prelude or the program defines.
+    sandboxed: bool,
+    last_error: Option<LastError>,
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+// The evaluations a connection is waiting for, by session and message id, so they can be
+// interrupted when it goes away.
+type Pending = Arc<Mutex<HashSet<(String, String)>>>;
+
+// The last error of a session, along with where the evaluated code came from.
+struct LastError {
+    error: ReplError,
+    origin: CodeOrigin,
+}
+
+impl LastError {
+    // The position of the error, shifted by where the evaluated code starts in the client's file.
+    fn frame(&self) -> Option<StackFrame> {
+        let (line, column) = self.error.location()?;
+        let (mut line, mut column) = (line as u64, column as u64);
+        if let Some(start) = self.origin.line {
+            if line == 1 {
+                column += self.origin.column.unwrap_or(1).saturating_sub(1);
+            }
+            line += start.saturating_sub(1);
+        }
+        let file = self.origin.file.clone().unwrap_or_else(|| "REPL".into());
+        return Some(StackFrame { name: "eval".into(), file, line, column });
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct StatusResponse {
+    id: MessageId,
+    session: String,
+    status: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 struct LsSessionsResponse {
-    sessions: Vec<u64>,
+    id: MessageId,
+    sessions: Vec<String>,
+    status: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 struct DescribeResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<MessageId>,
     aux: Vec<String>,
     ops: Vec<String>,
+    versions: BTreeMap<String, VersionResponse>,
+    status: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct VersionResponse {
+    major: u64,
+    minor: u64,
+    incremental: u64,
+    version_string: String,
+}
+
+impl VersionResponse {
+    fn new(version_string: &str) -> VersionResponse {
+        let mut parts = version_string.split('.').map(|p| p.parse().unwrap_or(0));
+        return VersionResponse {
+            major: parts.next().unwrap_or(0),
+            minor: parts.next().unwrap_or(0),
+            incremental: parts.next().unwrap_or(0),
+            version_string: version_string.into(),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct CompletionCandidate {
+    candidate: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct CompletionsResponse {
+    id: MessageId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    completions: Vec<CompletionCandidate>,
+    status: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct SymbolInfoResponse {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    arglists_str: String,
+    doc: String,
+}
+
+impl SymbolInfoResponse {
+    fn new(info: SymbolInfo) -> SymbolInfoResponse {
+        return SymbolInfoResponse { name: info.name, kind: info.kind, arglists_str: info.arglists, doc: info.doc };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct LookupResponse {
+    id: MessageId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    info: SymbolInfoResponse,
+    status: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct InfoResponse {
+    id: MessageId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    arglists_str: String,
+    doc: String,
+    status: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NReplConfiguration {
+    addr: String,
+    port: u16,
+    whitelist: Vec<String>,
+    port_file: Option<String>,
+    token: Option<String>,
+}
+
+impl NReplConfiguration {
+    pub fn new(addr: String, port: u16, whitelist: Vec<String>, port_file: Option<String>, token: Option<String>) -> NReplConfiguration {
+        return NReplConfiguration { addr, port, whitelist, port_file, token };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlSocketConfiguration {
+    path: String,
+    mode: u32,
+}
+
+impl ControlSocketConfiguration {
+    pub fn new(path: String, mode: u32) -> ControlSocketConfiguration {
+        return ControlSocketConfiguration { path, mode };
+    }
+}
+
+// The kinds of streams clients can connect over.
+trait NReplStream: Read + Write + Send + 'static {
+    fn try_clone_stream(&self) -> io::Result<Self> where Self: Sized;
+    // Who is on the other end, for log messages.
+    fn peer(&self) -> String;
+}
+
+impl NReplStream for TcpStream {
+    fn try_clone_stream(&self) -> io::Result<TcpStream> {
+        return self.try_clone();
+    }
+
+    fn peer(&self) -> String {
+        return self.peer_addr().map_or("unknown".into(), |addr| addr.to_string());
+    }
+}
+
+impl NReplStream for UnixStream {
+    fn try_clone_stream(&self) -> io::Result<UnixStream> {
+        return self.try_clone();
+    }
+
+    fn peer(&self) -> String {
+        return "on the control socket".into();
+    }
 }
 
-pub fn nrepl_thread(tx: mpsc::Sender<VMMessage>, whitelist: Vec<String>) {
-    let listener = TcpListener::bind("127.0.0.1:7888").unwrap();
+pub fn nrepl_thread(tx: mpsc::Sender<VMMessage>, config: NReplConfiguration, interrupt: SharedInterrupt) {
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+
+    let listener = match TcpListener::bind((config.addr.as_str(), config.port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to start nREPL server on {}:{}: {}", config.addr, config.port, e);
+            return;
+        },
+    };
 
-    let whitelist: Vec<IpAddr> = whitelist.iter()
+    // The port is read back from the listener, so that a configured port of 0 still leads
+    // editors to whichever port the system picked.
+    let port = listener.local_addr().unwrap().port();
+    log::info!("nREPL server listening on {}:{}.", config.addr, port);
+    if let Some(port_file) = &config.port_file {
+        if let Err(e) = fs::write(port_file, port.to_string()) {
+            log::warn!("Unable to write nREPL port file at {}: {}", port_file, e);
+        }
+    }
+
+    let whitelist: Vec<IpAddr> = config.whitelist.iter()
         .map(|addr| addr.parse().unwrap())
         .collect();
 
     for stream in listener.incoming() {
         let stream = stream.unwrap();
         if whitelist.contains(&stream.peer_addr().unwrap().ip()) {
-            stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
-
             let stream_tx = tx.clone();
+            let stream_interrupt = interrupt.clone();
+            let stream_token = config.token.clone();
+            let stream_sessions = sessions.clone();
             thread::spawn(|| {
-                handle_nrepl_connection(stream, stream_tx);
+                handle_nrepl_connection(stream, stream_tx, stream_interrupt, stream_token, stream_sessions);
             });
         }
     }
 }
 
-fn handle_nrepl_connection(mut stream: TcpStream, tx: mpsc::Sender<VMMessage>) {
-    let mut out_stream = stream.try_clone().unwrap();
+// Serves the nREPL protocol on a Unix socket. Access is controlled by the socket's file
+// permissions, so no token is required.
+pub fn control_socket_thread(tx: mpsc::Sender<VMMessage>, config: ControlSocketConfiguration, interrupt: SharedInterrupt) {
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+
+    // A socket left behind by a previous run would make binding fail. One that still accepts
+    // connections belongs to a running instance, and anything that isn't a socket is left alone.
+    if let Ok(metadata) = fs::symlink_metadata(&config.path) {
+        if metadata.file_type().is_socket() {
+            if UnixStream::connect(&config.path).is_ok() {
+                log::error!("Unable to create control socket at {}: another instance is listening on it.", config.path);
+                return;
+            }
+            let _ = fs::remove_file(&config.path);
+        }
+    }
+    let listener = match bind_private(&config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to create control socket at {}: {}", config.path, e);
+            return;
+        },
+    };
+    log::info!("Control socket listening at {}.", config.path);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let stream_tx = tx.clone();
+        let stream_interrupt = interrupt.clone();
+        let stream_sessions = sessions.clone();
+        thread::spawn(|| {
+            handle_nrepl_connection(stream, stream_tx, stream_interrupt, None, stream_sessions);
+        });
+    }
+}
+
+// Binds the socket inside a directory only heinzelmann can enter and moves it into place once
+// its permissions are set, so nobody can connect before that.
+fn bind_private(config: &ControlSocketConfiguration) -> io::Result<UnixListener> {
+    let dir = format!("{}.{}", config.path, utils::random_string(8));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = format!("{}/socket", dir);
+    let bound = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(config.mode))?;
+        fs::rename(&private_path, &config.path)?;
+        return Ok(listener);
+    });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&dir);
+    return bound;
+}
+
+fn handle_nrepl_connection<S: NReplStream>(mut stream: S, tx: mpsc::Sender<VMMessage>, interrupt: SharedInterrupt, token: Option<String>, sessions: Sessions) {
+    // Evaluations answer from their own thread so that an interrupt can still be read while
+    // they are running, which means writes to the stream have to be shared.
+    let out_stream = match stream.try_clone_stream() {
+        Ok(out_stream) => Arc::new(Mutex::new(out_stream)),
+        Err(_) => return,
+    };
+    let peer = stream.peer();
     let mut buf_reader = BufReader::new(&mut stream);
 
-    let mut sessions: Vec<u64> = Vec::new();
+    let mut authenticated = token.is_none();
+
+    let mut taps: HashMap<String, Arc<AtomicBool>> = HashMap::new();
+    let pending: Pending = Arc::new(Mutex::new(HashSet::new()));
 
     loop {
-        let mut line = String::new();
-        let _ = buf_reader.read_to_string(&mut line);
-
-        if line.len() > 0 {
-            let mut b = line.as_bytes();
-            let data: NReplMessage = bt_bencode::from_slice(&mut b).unwrap();
-
-            let encoded_data: Vec<u8> = match data {
-                NReplMessage::Clone { session: _, id } => {
-                    let new_session = match sessions.last() {
-                        Some(n) => n+1,
-                        None => 1,
-                    };
-                    sessions.push(new_session);
+        let message = match read_message(&mut buf_reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) => {
+                // After a framing error there is no telling where the next message starts, so
+                // the connection can't be used any further.
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let _ = out_stream.lock().unwrap().write_all(&ErrorResponse::encode(&MessageHeader::default(), &["error", "done"]));
+                }
+                break;
+            },
+        };
+
+        let header: MessageHeader = bt_bencode::from_slice(&message).unwrap_or_default();
+
+        // When a token is configured, the first message has to carry it. Anything else ends
+        // the connection.
+        if !authenticated {
+            let presented = bt_bencode::from_slice::<TokenHeader>(&message).ok().and_then(|t| t.token).unwrap_or_default();
+            if !constant_time_eq(presented.as_bytes(), token.as_deref().unwrap_or("").as_bytes()) {
+                log::warn!("nREPL client {} failed to authenticate.", peer);
+                let _ = out_stream.lock().unwrap().write_all(&ErrorResponse::encode(&header, &["error", "auth-failed", "done"]));
+                break;
+            }
+            authenticated = true;
+        }
+
+        let encoded_data: Vec<u8> = match bt_bencode::from_slice::<NReplMessage>(&message) {
+            Err(_) => match &header.op {
+                Some(op) if SUPPORTED_OPS.contains(&op.as_str()) => ErrorResponse::encode(&header, &["error", "done"]),
+                _ => ErrorResponse::encode(&header, &["error", "unknown-op", "done"]),
+            },
+            Ok(data) => match data {
+                NReplMessage::Clone { session, id, sandbox } => {
+                    let new_session = utils::random_uuid();
+                    let mut sessions = sessions.lock().unwrap();
+                    // A clone inherits its parent's sandbox and, inside the VM, its history.
+                    let parent_sandboxed = session.as_ref()
+                        .and_then(|s| sessions.get(s))
+                        .map_or(false, |s| s.sandboxed);
+                    let sandboxed = parent_sandboxed || sandbox.map_or(false, |n| n != 0);
+                    sessions.insert(new_session.clone(), Session { sandboxed, last_error: None });
+                    tx.send(VMMessage::CloneSession(session, new_session.clone())).unwrap();
 
                     let response = CloneResponse { id, new_session, status: vec!["done".into()] };
                     bt_bencode::to_vec(&response).unwrap()
                 },
-                NReplMessage::Eval { session, id, code, .. } => {
-                    if sessions.contains(&session) {
+                NReplMessage::Eval { session, id, code, file, line, column } => {
+                    let sandboxed = sessions.lock().unwrap().get(&session).map(|s| s.sandboxed);
+                    if let Some(sandboxed) = sandboxed {
                         let (repl_cmd, resp_rx) = ReplCommand::create(code);
+                        let repl_cmd = repl_cmd.in_session(ReplSession::new(session.clone(), sandboxed)).with_message_id(id.key());
                         tx.send(VMMessage::Command(repl_cmd)).unwrap();
 
-                        let value = match resp_rx.recv().unwrap() {
-                            ReplResponse::Empty => "()".into(),
-                            ReplResponse::Return(s) => s,
-                            ReplResponse::Error(_) => "Error".into(),
-                        };
-
-                        let response = EvalResponse { id, session, value, ns: "ns".into(), status: vec!["done".into()] };
-                        bt_bencode::to_vec(&response).unwrap()
+                        let origin = CodeOrigin { file, line, column };
+                        let eval_out_stream = out_stream.clone();
+                        let eval_sessions = sessions.clone();
+                        let eval_pending = pending.clone();
+                        eval_pending.lock().unwrap().insert((session.clone(), id.key()));
+                        thread::spawn(move || {
+                            answer_evaluation(resp_rx, id, session, origin, eval_out_stream, eval_sessions, eval_pending);
+                        });
+                        vec![]
                     }
                     else {
+                        ErrorResponse::encode(&header, &["error", "unknown-session", "done"])
+                    }
+                },
+                NReplMessage::LoadFile { id, session, file, file_name, file_path } => {
+                    let sandboxed = sessions.lock().unwrap().get(&session).map(|s| s.sandboxed);
+                    if let Some(sandboxed) = sandboxed {
+                        // Hooks registered by the file are attributed to it, and errors point
+                        // into the editor's buffer.
+                        let path = file_path.or(file_name).unwrap_or_else(|| "REPL".into());
+                        let (repl_cmd, resp_rx) = ReplCommand::create_from_file(file, path.clone());
+                        let repl_cmd = repl_cmd.in_session(ReplSession::new(session.clone(), sandboxed)).with_message_id(id.key());
+                        tx.send(VMMessage::Command(repl_cmd)).unwrap();
+
+                        let origin = CodeOrigin { file: Some(path), line: None, column: None };
+                        let eval_out_stream = out_stream.clone();
+                        let eval_sessions = sessions.clone();
+                        let eval_pending = pending.clone();
+                        eval_pending.lock().unwrap().insert((session.clone(), id.key()));
+                        thread::spawn(move || {
+                            answer_evaluation(resp_rx, id, session, origin, eval_out_stream, eval_sessions, eval_pending);
+                        });
                         vec![]
                     }
+                    else {
+                        ErrorResponse::encode(&header, &["error", "unknown-session", "done"])
+                    }
                 },
-                NReplMessage::Describe => {
+                NReplMessage::Interrupt { id, session, interrupt_id } => {
+                    let known = sessions.lock().unwrap().contains_key(&session);
+                    let status = match known && interrupt.request(&session, interrupt_id.as_ref().map(MessageId::key).as_deref()) {
+                        true => "done",
+                        false => "session-idle",
+                    };
+                    let response = StatusResponse { id, session, status: vec![status.into()] };
+                    bt_bencode::to_vec(&response).unwrap()
+                },
+                NReplMessage::Describe { id } => {
+                    let mut versions = BTreeMap::new();
+                    versions.insert(PROGRAM_NAME.into(), VersionResponse::new(env!("CARGO_PKG_VERSION")));
+                    versions.insert("steel".into(), VersionResponse::new(docs::STEEL_VERSION));
                     let capabilities = DescribeResponse {
-                        ops: vec![
-                            "clone".into(), 
-                            "eval".into(), 
-                            "describe".into(), 
-                            "ls-sessions".into(),
-                            "close".into(),
-                        ],
+                        id,
+                        ops: SUPPORTED_OPS.iter().map(|op| op.to_string()).collect(),
                         aux: vec![],
+                        versions,
+                        status: vec!["done".into()],
                     };
                     bt_bencode::to_vec(&capabilities).unwrap()
                 },
-                NReplMessage::LsSessions => {
-                    let response = LsSessionsResponse { sessions: sessions.clone() };
+                // The VM only answers these once it is done evaluating, so like evaluations they are
+                // answered from their own thread.
+                NReplMessage::Completions { id, session, prefix } | NReplMessage::Complete { id, session, prefix } => {
+                    let (completions_tx, completions_rx) = mpsc::channel();
+                    tx.send(VMMessage::Completions(prefix, completions_tx)).unwrap();
+                    let completions_out_stream = out_stream.clone();
+                    thread::spawn(move || {
+                        let completions = completions_rx.recv().unwrap_or_default().into_iter()
+                            .map(|c| CompletionCandidate { candidate: c.candidate, kind: c.kind })
+                            .collect();
+                        let response = CompletionsResponse { id, session, completions, status: vec!["done".into()] };
+                        let _ = completions_out_stream.lock().unwrap().write_all(&bt_bencode::to_vec(&response).unwrap());
+                    });
+                    vec![]
+                },
+                NReplMessage::Lookup { id, session, sym } => {
+                    let (lookup_tx, lookup_rx) = mpsc::channel();
+                    tx.send(VMMessage::Lookup(sym, lookup_tx)).unwrap();
+                    let no_info = ErrorResponse::encode(&header, &["no-info", "done"]);
+                    let lookup_out_stream = out_stream.clone();
+                    thread::spawn(move || {
+                        let encoded_data = match lookup_rx.recv().ok().flatten() {
+                            Some(info) => {
+                                let response = LookupResponse { id, session, info: SymbolInfoResponse::new(info), status: vec!["done".into()] };
+                                bt_bencode::to_vec(&response).unwrap()
+                            },
+                            None => no_info,
+                        };
+                        let _ = lookup_out_stream.lock().unwrap().write_all(&encoded_data);
+                    });
+                    vec![]
+                },
+                NReplMessage::Info { id, session, sym } => {
+                    let (lookup_tx, lookup_rx) = mpsc::channel();
+                    tx.send(VMMessage::Lookup(sym, lookup_tx)).unwrap();
+                    let no_info = ErrorResponse::encode(&header, &["no-info", "done"]);
+                    let info_out_stream = out_stream.clone();
+                    thread::spawn(move || {
+                        let encoded_data = match lookup_rx.recv().ok().flatten() {
+                            Some(info) => {
+                                let response = InfoResponse {
+                                    id,
+                                    session,
+                                    name: info.name,
+                                    kind: info.kind,
+                                    arglists_str: info.arglists,
+                                    doc: info.doc,
+                                    status: vec!["done".into()],
+                                };
+                                bt_bencode::to_vec(&response).unwrap()
+                            },
+                            None => no_info,
+                        };
+                        let _ = info_out_stream.lock().unwrap().write_all(&encoded_data);
+                    });
+                    vec![]
+                },
+                NReplMessage::LsSessions { id } => {
+                    let mut sessions: Vec<String> = sessions.lock().unwrap().keys().cloned().collect();
+                    sessions.sort();
+                    let response = LsSessionsResponse { id, sessions, status: vec!["done".into()] };
                     bt_bencode::to_vec(&response).unwrap()
                 },
-                NReplMessage::Close { session } => {
-                    if let Ok(i) = sessions.binary_search(&session) {
-                        sessions.remove(i);
+                NReplMessage::Close { id, session } => {
+                    if let Some(tap) = taps.remove(&session) {
+                        tap.store(false, Ordering::Relaxed);
                     }
-                    vec![]
-                }
-                _ => vec![],
+                    if sessions.lock().unwrap().remove(&session).is_some() {
+                        tx.send(VMMessage::CloseSession(session.clone())).unwrap();
+                        let response = StatusResponse { id, session, status: vec!["session-closed".into(), "done".into()] };
+                        bt_bencode::to_vec(&response).unwrap()
+                    }
+                    else {
+                        ErrorResponse::encode(&header, &["error", "unknown-session", "done"])
+                    }
+                },
+                NReplMessage::OutSubscribe { id, session } => {
+                    if sessions.lock().unwrap().contains_key(&session) && !taps.contains_key(&session) {
+                        // Output from event and timer handlers is forwarded to the session until
+                        // it unsubscribes. The VM forgets the tap once the forwarding stops.
+                        let (tap_tx, tap_rx) = mpsc::channel();
+                        tx.send(VMMessage::Tap(tap_tx)).unwrap();
+                        let active = Arc::new(AtomicBool::new(true));
+                        taps.insert(session.clone(), active.clone());
+
+                        let tap_out_stream = out_stream.clone();
+                        let tap_session = session.clone();
+                        thread::spawn(move || {
+                            for response in tap_rx {
+                                if !active.load(Ordering::Relaxed) {
+                                    break;
+                                }
+                                if let Some(encoded_data) = OutputResponse::encode(None, tap_session.clone(), &response) {
+                                    if tap_out_stream.lock().unwrap().write_all(&encoded_data).is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                        });
+                    }
+                    let response = StatusResponse { id, session, status: vec!["done".into()] };
+                    bt_bencode::to_vec(&response).unwrap()
+                },
+                NReplMessage::Stacktrace { id, session } => {
+                    let sessions = sessions.lock().unwrap();
+                    match sessions.get(&session).and_then(|s| s.last_error.as_ref()) {
+                        Some(last_error) => {
+                            let response = StacktraceResponse {
+                                id,
+                                session: session.clone(),
+                                class: last_error.error.kind().into(),
+                                message: last_error.error.message().into(),
+                                stacktrace: last_error.frame().into_iter().collect(),
+                                status: vec!["done".into()],
+                            };
+                            bt_bencode::to_vec(&response).unwrap()
+                        },
+                        None => {
+                            let response = StatusResponse { id, session, status: vec!["no-error".into(), "done".into()] };
+                            bt_bencode::to_vec(&response).unwrap()
+                        },
+                    }
+                },
+                NReplMessage::OutUnsubscribe { id, session } => {
+                    if let Some(tap) = taps.remove(&session) {
+                        tap.store(false, Ordering::Relaxed);
+                    }
+                    let response = StatusResponse { id, session, status: vec!["done".into()] };
+                    bt_bencode::to_vec(&response).unwrap()
+                },
+                _ => ErrorResponse::encode(&header, &["error", "unknown-op", "done"]),
+            },
+        };
+
+        let _ = out_stream.lock().unwrap().write_all(&encoded_data);
+    }
+
+    for tap in taps.values() {
+        tap.store(false, Ordering::Relaxed);
+    }
+    // Nobody is left to see the results, or to interrupt evaluations that don't end.
+    for (session, id) in pending.lock().unwrap().drain() {
+        interrupt.abandon(&session, &id);
+    }
+}
+
+// Waits for the VM to finish an evaluation, forwarding its output and finally its result.
+fn answer_evaluation<S: NReplStream>(resp_rx: mpsc::Receiver<ReplResponse>, id: MessageId, session: String, origin: CodeOrigin, out_stream: Arc<Mutex<S>>, sessions: Sessions, pending: Pending) {
+    let (session_key, id_key) = (session.clone(), id.key());
+    let mut response = resp_rx.recv().unwrap();
+    while let Some(encoded_data) = OutputResponse::encode(Some(id.clone()), session.clone(), &response) {
+        let _ = out_stream.lock().unwrap().write_all(&encoded_data);
+        response = resp_rx.recv().unwrap();
+    }
+    let encoded_data = match response {
+        ReplResponse::Interrupted => {
+            let response = StatusResponse { id, session, status: vec!["interrupted".into(), "done".into()] };
+            bt_bencode::to_vec(&response).unwrap()
+        },
+        ReplResponse::Error(error) => {
+            // Like nREPL itself, the error is printed to err first and then reported as the
+            // result of the evaluation.
+            let mut encoded_data = OutputResponse::encode(Some(id.clone()), session.clone(), &ReplResponse::Stderr(format!("{}\n", error))).unwrap();
+            let response = EvalErrorResponse {
+                id,
+                session: session.clone(),
+                ex: error.kind().into(),
+                root_ex: error.kind().into(),
+                status: vec!["eval-error".into(), "done".into()],
+            };
+            encoded_data.extend(bt_bencode::to_vec(&response).unwrap());
+            if let Some(session) = sessions.lock().unwrap().get_mut(&session) {
+                session.last_error = Some(LastError { error, origin });
+            }
+            encoded_data
+        },
+        r => {
+            let value = match r {
+                ReplResponse::Return(s) => s,
+                _ => "()".into(),
             };
+            let response = EvalResponse { id, session, value, ns: "ns".into(), status: vec!["done".into()] };
+            bt_bencode::to_vec(&response).unwrap()
+        },
+    };
+    pending.lock().unwrap().remove(&(session_key, id_key));
+    let _ = out_stream.lock().unwrap().write_all(&encoded_data);
+}
+
+// Compares two byte strings in time independent of where they differ, so the token can't be
+// guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+// Reads exactly one bencoded message from the stream. Returns None if the stream ended cleanly
+// between two messages.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut message = Vec::new();
+    read_bencode_value(reader, &mut message, 0)?;
+    return Ok(Some(message));
+}
+
+fn read_bencode_value<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>, depth: usize) -> io::Result<()> {
+    if depth > MAX_NESTING_DEPTH || buf.len() > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bencode message too large"));
+    }
+    let first = read_byte(reader, buf)?;
+    match first {
+        b'i' => {
+            loop {
+                if buf.len() > MAX_MESSAGE_LENGTH {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bencode message too large"));
+                }
+                match read_byte(reader, buf)? {
+                    b'e' => break,
+                    b'-' | b'0'..=b'9' => {},
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bencode integer")),
+                }
+            }
+        },
+        b'l' | b'd' => {
+            loop {
+                match reader.fill_buf()?.first() {
+                    Some(b'e') => {
+                        read_byte(reader, buf)?;
+                        break;
+                    },
+                    Some(_) => read_bencode_value(reader, buf, depth + 1)?,
+                    None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+        },
+        b'0'..=b'9' => {
+            let mut length = (first - b'0') as usize;
+            loop {
+                match read_byte(reader, buf)? {
+                    b':' => break,
+                    c @ b'0'..=b'9' => {
+                        length = length.saturating_mul(10).saturating_add((c - b'0') as usize);
+                    },
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bencode string length")),
+                }
+            }
+            if length > MAX_MESSAGE_LENGTH {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bencode message too large"));
+            }
+            let start = buf.len();
+            buf.resize(start + length, 0);
+            reader.read_exact(&mut buf[start..])?;
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bencode value")),
+    }
+    return Ok(());
+}
+
+fn read_byte<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    buf.push(byte[0]);
+    return Ok(byte[0]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_pipelined_messages_one_at_a_time() {
+        let mut reader = Cursor::new(b"d2:op5:clone2:idi1eed2:op8:describee".to_vec());
+        assert_eq!(read_message(&mut reader).unwrap(), Some(b"d2:op5:clone2:idi1ee".to_vec()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(b"d2:op8:describee".to_vec()));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_nested_values() {
+        let mut reader = Cursor::new(b"d4:listl1:ai-3ed1:bi0eeee".to_vec());
+        assert_eq!(read_message(&mut reader).unwrap(), Some(b"d4:listl1:ai-3ed1:bi0eeee".to_vec()));
+    }
+
+    #[test]
+    fn clean_eof_between_messages_is_not_an_error() {
+        let mut reader = Cursor::new(Vec::new());
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_message_is_unexpected_eof() {
+        for input in [&b"d2:op"[..], b"d2:op5:clo", b"d2:op5:clonei12", b"d2:op5:clone2:id"] {
+            let mut reader = Cursor::new(input.to_vec());
+            assert_eq!(read_message(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn oversized_string_length_is_refused() {
+        let mut reader = Cursor::new(b"d4:code99999999999999999999:(+ 1 2)e".to_vec());
+        assert_eq!(read_message(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let input: Vec<u8> = std::iter::repeat(b'l').take(MAX_NESTING_DEPTH + 2).collect();
+        let mut reader = Cursor::new(input);
+        assert_eq!(read_message(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn endless_integer_is_refused() {
+        let mut input = b"d2:idi".to_vec();
+        input.extend(std::iter::repeat(b'1').take(MAX_MESSAGE_LENGTH + 1));
+        let mut reader = Cursor::new(input);
+        assert_eq!(read_message(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
 
-            let _ = out_stream.write_all(&encoded_data);
+    #[test]
+    fn invalid_values_are_refused() {
+        for input in [&b"x"[..], b"d2:op5:clone3x:abe", b"d2:idi1x2ee"] {
+            let mut reader = Cursor::new(input.to_vec());
+            assert_eq!(read_message(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
         }
     }
 }
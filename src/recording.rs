@@ -0,0 +1,178 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Recordings of incoming MQTT traffic, to be replayed offline with `heinzelmann replay`.
+//
+// A recording has one message per line: the time it arrived (RFC 3339), its topic and its
+// payload, separated by tabs. Tabs, newlines and backslashes in the topic and payload are
+// escaped with a backslash.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use chrono::{DateTime, Local};
+use crate::TimedEvent;
+
+pub struct RecordedMessage {
+    pub time: DateTime<Local>,
+    pub topic: String,
+    pub payload: String,
+}
+
+fn escape(s: &str) -> String {
+    return s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r");
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            // Only hand edited recordings have other escapes, which are kept as they are.
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            },
+            None => unescaped.push('\\'),
+        }
+    }
+    return unescaped;
+}
+
+pub struct Recorder {
+    file: File,
+    path: String,
+}
+
+impl Recorder {
+    pub fn open(path: &str) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        return Ok(Recorder { file, path: path.into() });
+    }
+
+    pub fn record(&mut self, topic: &str, payload: &str) {
+        let line = format!("{}\t{}\t{}\n", Local::now().to_rfc3339(), escape(topic), escape(payload));
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            log::error!("Unable to write to the recording at {}: {}", self.path, e);
+        }
+    }
+}
+
+pub fn read(path: &str) -> Result<Vec<RecordedMessage>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read recording at {}: {}", path, e))?;
+    let mut messages = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("{}:{}: not a recorded message.", path, number + 1);
+        let mut fields = line.splitn(3, '\t');
+        let time = fields.next()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .ok_or_else(invalid)?
+            .with_timezone(&Local);
+        let topic = fields.next().ok_or_else(invalid)?;
+        let payload = fields.next().ok_or_else(invalid)?;
+        messages.push(RecordedMessage { time, topic: unescape(topic), payload: unescape(payload) });
+    }
+    messages.sort_by_key(|message| message.time);
+    return Ok(messages);
+}
+
+// The timers set by the program, as they fire on the simulated clock.
+pub struct TimerSchedule {
+    timers: Vec<(DateTime<Local>, TimedEvent)>,
+}
+
+impl TimerSchedule {
+    pub fn new() -> TimerSchedule {
+        return TimerSchedule { timers: vec![] };
+    }
+
+    // Like the timer thread, a timer set again at another time is moved rather than added.
+    pub fn add(&mut self, timer: TimedEvent, now: DateTime<Local>) {
+        match self.timers.iter_mut().find(|(_, t)| t.id == timer.id) {
+            Some((_, known)) if *known == timer => {},
+            Some(known) => *known = (timer.next_time_after(now), timer),
+            None => self.timers.push((timer.next_time_after(now), timer)),
+        }
+    }
+
+    // The next timer to fire no later than until, if any. It is then scheduled for the next day.
+    pub fn next_due(&mut self, until: DateTime<Local>) -> Option<(DateTime<Local>, String)> {
+        let (next, timer) = self.timers.iter_mut()
+            .filter(|(next, _)| *next <= until)
+            .min_by_key(|(next, _)| *next)?;
+        let due = *next;
+        *next = due + chrono::Duration::days(1);
+        return Some((due, timer.id.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn escaping_round_trips() {
+        for s in ["plain", "tab\there", "two\nlines\r\n", "back\\slash", "\\t is not a tab", ""] {
+            let escaped = escape(s);
+            assert!(!escaped.contains('\t') && !escaped.contains('\n') && !escaped.contains('\r'));
+            assert_eq!(unescape(&escaped), s);
+        }
+    }
+
+    #[test]
+    fn unescaping_keeps_unknown_escapes_and_a_trailing_backslash() {
+        assert_eq!(unescape("a\\qb"), "a\\qb");
+        assert_eq!(unescape("end\\"), "end\\");
+    }
+
+    #[test]
+    fn reads_recordings_sorted_by_time() {
+        let path = std::env::temp_dir().join(format!("heinzelmann-recording-{}", std::process::id()));
+        let contents = "2024-01-01T10:00:05+00:00\thome/b\tsecond\n\n2024-01-01T10:00:00+00:00\thome/a\tfirst\\twith a tab\n";
+        fs::write(&path, contents).unwrap();
+        let messages = read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let messages = messages.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].topic, "home/a");
+        assert_eq!(messages[0].payload, "first\twith a tab");
+        assert_eq!(messages[1].topic, "home/b");
+    }
+
+    #[test]
+    fn refuses_lines_that_are_not_messages() {
+        let path = std::env::temp_dir().join(format!("heinzelmann-bad-recording-{}", std::process::id()));
+        fs::write(&path, "2024-01-01T10:00:00+00:00\thome/a\tok\nnot a time\thome/b\tpayload\n").unwrap();
+        let result = read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(e) if e.ends_with(":2: not a recorded message.")));
+    }
+
+    #[test]
+    fn timers_set_again_at_another_time_are_moved() {
+        let now = Local.with_ymd_and_hms(2024, 1, 1, 6, 0, 0).unwrap();
+        let mut timers = TimerSchedule::new();
+        timers.add(TimedEvent::new((7, 0), "morning".into()), now);
+        timers.add(TimedEvent::new((8, 30), "morning".into()), now);
+        let until = Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let (due, id) = timers.next_due(until).unwrap();
+        assert_eq!(id, "morning");
+        assert_eq!(due, Local.with_ymd_and_hms(2024, 1, 1, 8, 30, 0).unwrap());
+        assert!(timers.next_due(until).is_none());
+    }
+}
@@ -0,0 +1,371 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io::Read;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use rustyline::{Editor, ExternalPrinter, Helper, Context};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{Validator, ValidationContext, ValidationResult};
+use crate::{VMMessage, ReplCommand, ReplResponse, SharedInterrupt};
+
+const META_HELP: &'static str = ",hooks          registered event and timer hooks
+,subs           active subscriptions
+,timers         timers and when they fire next
+,last <topic>   the last message received on topic
+,stats          message and handler counters
+,reload         runs the program file again
+,watch <filter> prints messages matching filter as they arrive
+,unwatch [filter]
+                stops watching filter, or everything
+,inject <topic> <payload>
+                runs the event hooks as if payload had arrived on topic
+";
+
+// Commands starting with a comma inspect the running system instead of evaluating code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaCommand {
+    Hooks,
+    Subs,
+    Timers,
+    Last(String),
+    Stats,
+    Reload,
+    Watch(String),
+    Unwatch(Option<String>),
+    Inject(String, String),
+}
+
+impl MetaCommand {
+    fn parse(line: &str) -> Result<MetaCommand, String> {
+        let mut words = line.trim().trim_start_matches(',').splitn(2, char::is_whitespace);
+        let command = words.next().unwrap_or("");
+        let argument = words.next().map(str::trim).filter(|a| !a.is_empty());
+        match (command, argument) {
+            ("hooks", None) => return Ok(MetaCommand::Hooks),
+            ("subs", None) => return Ok(MetaCommand::Subs),
+            ("timers", None) => return Ok(MetaCommand::Timers),
+            ("last", Some(topic)) => return Ok(MetaCommand::Last(topic.into())),
+            ("stats", None) => return Ok(MetaCommand::Stats),
+            ("reload", None) => return Ok(MetaCommand::Reload),
+            ("watch", Some(filter)) => return Ok(MetaCommand::Watch(filter.into())),
+            ("unwatch", filter) => return Ok(MetaCommand::Unwatch(filter.map(String::from))),
+            ("inject", Some(message)) => {
+                if let Some((topic, payload)) = message.split_once(char::is_whitespace) {
+                    return Ok(MetaCommand::Inject(topic.into(), payload.trim_start().into()));
+                }
+            },
+            _ => {},
+        }
+        return Err(format!("Unknown command '{}'. Available commands:\n{}", line.trim(), META_HELP));
+    }
+}
+
+// Characters that end a symbol when looking for the word to complete.
+const DELIMITERS: &[char] = &['(', ')', '[', ']', '\'', '`', ',', '"'];
+
+// Looks up the globals starting with a prefix, either in the VM or over the control socket.
+pub type CompletionSource = Box<dyn Fn(&str) -> Vec<String>>;
+
+pub struct ReplHelper {
+    complete: CompletionSource,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || DELIMITERS.contains(&c))
+            .map_or(0, |i| i + 1);
+        let prefix = &line[start..pos];
+        if prefix.is_empty() {
+            return Ok((pos, vec![]));
+        }
+
+        return Ok((start, (self.complete)(prefix)));
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+// Keeps asking for more lines until every opened paren is closed.
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if open_parens(ctx.input()) > 0 {
+            return Ok(ValidationResult::Incomplete);
+        }
+        return Ok(ValidationResult::Valid(None));
+    }
+}
+
+impl Helper for ReplHelper {}
+
+// Counts the parens and brackets that are still open at the end of code, skipping strings,
+// comments and character literals.
+fn open_parens(code: &str) -> i64 {
+    let mut depth = 0;
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ';' => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            },
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        },
+                        '"' => break,
+                        _ => {},
+                    }
+                }
+            },
+            '#' if chars.peek() == Some(&'\\') => {
+                chars.next();
+                chars.next();
+            },
+            _ => {},
+        }
+    }
+    return depth;
+}
+
+// Where SIGINT writes a byte, so that Ctrl-C can be handled on a thread of its own.
+static CTRL_C_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    let byte = 0u8;
+    unsafe {
+        libc::write(CTRL_C_FD.load(Ordering::Relaxed), &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+// Calls on_ctrl_c whenever Ctrl-C is pressed while an evaluation runs, instead of ending the
+// process. While the line editor reads input, it handles Ctrl-C itself.
+pub fn handle_ctrl_c<F: Fn() + Send + 'static>(on_ctrl_c: F) {
+    let (mut reader, writer) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => {
+            log::warn!("Unable to handle Ctrl-C: {}", e);
+            return;
+        },
+    };
+    CTRL_C_FD.store(writer.into_raw_fd(), Ordering::Relaxed);
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as extern "C" fn(libc::c_int) as *const () as libc::sighandler_t);
+    }
+    thread::spawn(move || {
+        let mut byte = [0u8; 1];
+        while reader.read_exact(&mut byte).is_ok() {
+            on_ctrl_c();
+        }
+    });
+}
+
+// Lets Ctrl-C end the process again.
+pub fn stop_handling_ctrl_c() {
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+pub fn create_editor(complete: CompletionSource, history_file: &Option<String>) -> Editor<ReplHelper, DefaultHistory> {
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new().unwrap();
+    editor.set_helper(Some(ReplHelper { complete }));
+    if let Some(history_file) = history_file {
+        // The file doesn't exist on the first start, which is fine.
+        let _ = editor.load_history(history_file);
+    }
+    return editor;
+}
+
+// Reads the next complete, non-empty input and adds it to the history. Returns None once the
+// input ends.
+pub fn read_input(editor: &mut Editor<ReplHelper, DefaultHistory>, history_file: &Option<String>) -> Option<String> {
+    loop {
+        let line = match editor.readline(">>> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return None,
+            Err(e) => {
+                println!("Unable to read from the REPL: {}", e);
+                return None;
+            },
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if let Some(history_file) = history_file {
+            let _ = editor.save_history(history_file);
+        }
+        return Some(line);
+    }
+}
+
+pub fn repl_thread(tx: mpsc::Sender<VMMessage>, history_file: Option<String>, interrupt: SharedInterrupt) {
+    // Ctrl-C interrupts the running evaluation rather than all of heinzelmann.
+    handle_ctrl_c(move || {
+        if interrupt.request("repl", None) {
+            eprintln!("Interrupting...");
+        }
+    });
+    let completions_tx = tx.clone();
+    let complete: CompletionSource = Box::new(move |prefix| {
+        let (candidates_tx, candidates_rx) = mpsc::channel();
+        if completions_tx.send(VMMessage::Completions(prefix.into(), candidates_tx)).is_err() {
+            return vec![];
+        }
+        return candidates_rx.recv()
+            .map(|completions| completions.into_iter().map(|c| c.candidate).collect())
+            .unwrap_or_default();
+    });
+    let mut editor = create_editor(complete, &history_file);
+
+    // Watched messages arrive while the user may be typing, so they are printed above the prompt.
+    let (watch_tx, watch_rx) = mpsc::channel();
+    match editor.create_external_printer() {
+        Ok(mut printer) => thread::spawn(move || {
+            for response in watch_rx {
+                if let ReplResponse::Stdout(s) | ReplResponse::Stderr(s) = response {
+                    let _ = printer.print(s);
+                }
+            }
+        }),
+        Err(_) => thread::spawn(move || print_responses_forever(watch_rx)),
+    };
+
+    while let Some(tx_line) = read_input(&mut editor, &history_file) {
+        if tx_line.starts_with("(quit)") {
+            break;
+        }
+        if tx_line.trim_start().starts_with(',') {
+            match MetaCommand::parse(&tx_line) {
+                Ok(MetaCommand::Watch(filter)) => {
+                    tx.send(VMMessage::Meta(MetaCommand::Watch(filter), watch_tx.clone())).unwrap();
+                },
+                Ok(command) => {
+                    // Reloading and injecting evaluates the program, so its result is shown like any other.
+                    let show_result = matches!(command, MetaCommand::Reload | MetaCommand::Inject(_, _));
+                    let (resp_tx, resp_rx) = mpsc::channel();
+                    tx.send(VMMessage::Meta(command, resp_tx)).unwrap();
+                    print_responses(resp_rx, show_result);
+                },
+                Err(e) => eprint!("{}", e),
+            }
+            continue;
+        }
+        let (repl_cmd, resp_rx) = ReplCommand::create(tx_line);
+        tx.send(VMMessage::Command(repl_cmd)).unwrap();
+        print_responses(resp_rx, true);
+    }
+    stop_handling_ctrl_c();
+}
+
+// Prints output until the final response arrives. Without show_result, an empty result is not
+// echoed.
+fn print_responses(resp_rx: mpsc::Receiver<ReplResponse>, show_result: bool) {
+    loop {
+        match resp_rx.recv().unwrap() {
+            ReplResponse::Stdout(s) => {
+                print!("{}", s);
+                continue;
+            },
+            ReplResponse::Stderr(s) => {
+                eprint!("{}", s);
+                continue;
+            },
+            ReplResponse::Empty => if show_result {
+                println!("=> ()");
+            },
+            ReplResponse::Return(s) => println!("=> {}", s),
+            ReplResponse::Error(e) => eprintln!("{}", e),
+            ReplResponse::Interrupted => println!("Interrupted."),
+        }
+        break;
+    }
+}
+
+fn print_responses_forever(resp_rx: mpsc::Receiver<ReplResponse>) {
+    for response in resp_rx {
+        match response {
+            ReplResponse::Stdout(s) => print!("{}", s),
+            ReplResponse::Stderr(s) => eprint!("{}", s),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_open_parens_and_brackets() {
+        assert_eq!(open_parens("(define x 1)"), 0);
+        assert_eq!(open_parens("(define (f x)"), 1);
+        assert_eq!(open_parens("(let ([x 1]"), 2);
+        assert_eq!(open_parens("(define (f x)\n  (+ x 1))"), 0);
+        assert_eq!(open_parens("x)"), -1);
+    }
+
+    #[test]
+    fn ignores_parens_in_strings_comments_and_characters() {
+        assert_eq!(open_parens(r#"(display "(")"#), 0);
+        assert_eq!(open_parens(r#"(display "\")[")"#), 0);
+        assert_eq!(open_parens("(list #\\( #\\[ #\\))"), 0);
+        assert_eq!(open_parens("(f ; (\n x)"), 0);
+        assert_eq!(open_parens("(display \"(\n"), 1);
+    }
+
+    #[test]
+    fn parses_meta_commands() {
+        assert_eq!(MetaCommand::parse(",hooks"), Ok(MetaCommand::Hooks));
+        assert_eq!(MetaCommand::parse("  ,subs  "), Ok(MetaCommand::Subs));
+        assert_eq!(MetaCommand::parse(",timers"), Ok(MetaCommand::Timers));
+        assert_eq!(MetaCommand::parse(",stats"), Ok(MetaCommand::Stats));
+        assert_eq!(MetaCommand::parse(",reload"), Ok(MetaCommand::Reload));
+        assert_eq!(MetaCommand::parse(",last  home/lamp/state "), Ok(MetaCommand::Last("home/lamp/state".into())));
+    }
+
+    #[test]
+    fn refuses_unknown_or_incomplete_meta_commands() {
+        for line in [",last", ",hooks now", ",reboot", ","] {
+            assert!(MetaCommand::parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn parses_watch_and_unwatch() {
+        assert_eq!(MetaCommand::parse(",watch home/+/state"), Ok(MetaCommand::Watch("home/+/state".into())));
+        assert_eq!(MetaCommand::parse(",unwatch home/#"), Ok(MetaCommand::Unwatch(Some("home/#".into()))));
+        assert_eq!(MetaCommand::parse(",unwatch"), Ok(MetaCommand::Unwatch(None)));
+        assert!(MetaCommand::parse(",watch").is_err());
+    }
+
+    #[test]
+    fn injects_the_payload_as_written() {
+        assert_eq!(MetaCommand::parse(r#",inject home/lamp {"state": "on", "note": "a \"b\""}"#),
+            Ok(MetaCommand::Inject("home/lamp".into(), r#"{"state": "on", "note": "a \"b\""}"#.into())));
+        assert_eq!(MetaCommand::parse(",inject home/lamp   two  words"), Ok(MetaCommand::Inject("home/lamp".into(), "two  words".into())));
+        assert!(MetaCommand::parse(",inject home/lamp").is_err());
+    }
+}
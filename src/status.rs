@@ -0,0 +1,291 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// What the running system has been up to, collected from the MQTT connection, the timer thread
+// and the VM so it can be inspected from the REPL.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use crate::{utils, TimedEvent, ReplResponse};
+use crate::metrics::{Exposition, Histogram, CustomMetrics};
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+// The VM counts as unresponsive when it hasn't answered a heartbeat for this long.
+const VM_STALL: Duration = Duration::from_secs(30);
+
+pub struct LastMessage {
+    pub payload: String,
+    pub received: DateTime<Local>,
+}
+
+pub struct Status {
+    started: Instant,
+    subscriptions: Vec<String>,
+    timers: Vec<TimedEvent>,
+    last_messages: HashMap<String, LastMessage>,
+    // Topic filters watched from the REPL, and where to send matching messages.
+    watches: Vec<(String, mpsc::Sender<ReplResponse>)>,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub handler_runs: u64,
+    pub handler_errors: u64,
+    pub handler_aborts: u64,
+    // Messages are counted by the first of these filters that matches their topic, then by the
+    // first matching subscription, and by "#" otherwise.
+    metric_filters: Vec<String>,
+    received_by_filter: BTreeMap<String, u64>,
+    published_by_filter: BTreeMap<String, u64>,
+    pub handler_latency: Histogram,
+    pub timers_fired: u64,
+    // Handler runs sent to the VM that haven't started yet.
+    pub pending_handlers: u64,
+    pub connections: u64,
+    pub nrepl_sessions: u64,
+    pub nrepl_sessions_created: u64,
+    pub custom_metrics: CustomMetrics,
+    pub broker_connected: bool,
+    pub program_loaded: bool,
+    ready: bool,
+    // When the VM last answered a heartbeat.
+    pub vm_heartbeat: Instant,
+}
+
+impl Status {
+    pub fn new() -> Status {
+        return Status {
+            started: Instant::now(),
+            subscriptions: vec![],
+            timers: vec![],
+            last_messages: HashMap::new(),
+            watches: vec![],
+            messages_received: 0,
+            messages_sent: 0,
+            handler_runs: 0,
+            handler_errors: 0,
+            handler_aborts: 0,
+            metric_filters: vec![],
+            received_by_filter: BTreeMap::new(),
+            published_by_filter: BTreeMap::new(),
+            handler_latency: Histogram::latency(),
+            timers_fired: 0,
+            pending_handlers: 0,
+            connections: 0,
+            nrepl_sessions: 0,
+            nrepl_sessions_created: 0,
+            custom_metrics: CustomMetrics::new(),
+            broker_connected: false,
+            program_loaded: false,
+            ready: false,
+            vm_heartbeat: Instant::now(),
+        };
+    }
+
+    pub fn shared() -> SharedStatus {
+        return Arc::new(Mutex::new(Status::new()));
+    }
+
+    pub fn uptime(&self) -> Duration {
+        return self.started.elapsed();
+    }
+
+    // Returns true only the first time the program has run and the broker is connected.
+    pub fn mark_ready(&mut self) -> bool {
+        if self.ready || !self.program_loaded || !self.broker_connected {
+            return false;
+        }
+        self.ready = true;
+        return true;
+    }
+
+    pub fn vm_responsive(&self) -> bool {
+        return self.vm_heartbeat.elapsed() < VM_STALL;
+    }
+
+    pub fn healthy(&self) -> bool {
+        return self.program_loaded && self.broker_connected && self.vm_responsive();
+    }
+
+    // A JSON report for the health endpoint.
+    pub fn health_listing(&self) -> String {
+        return format!(
+            "{{\"status\":\"{}\",\"program\":\"{}\",\"broker\":\"{}\",\"vm\":\"{}\",\"vm_heartbeat_age_seconds\":{},\"uptime_seconds\":{}}}\n",
+            if self.healthy() { "ok" } else { "unhealthy" },
+            if self.program_loaded { "loaded" } else { "loading" },
+            if self.broker_connected { "connected" } else { "disconnected" },
+            if self.vm_responsive() { "responsive" } else { "unresponsive" },
+            self.vm_heartbeat.elapsed().as_secs(),
+            self.uptime().as_secs());
+    }
+
+    pub fn set_metric_filters(&mut self, filters: Vec<String>) {
+        self.metric_filters = filters;
+    }
+
+    fn metric_filter(&self, topic: &str) -> String {
+        return self.metric_filters.iter()
+            .chain(self.subscriptions.iter())
+            .find(|filter| utils::topic_matches(filter, topic))
+            .cloned()
+            .unwrap_or("#".into());
+    }
+
+    // Returns the watched filters the broker was subscribed to for the REPL alone, which the new
+    // subscription overlaps.
+    pub fn record_subscription(&mut self, topic: &str) -> Vec<String> {
+        if self.subscriptions.iter().any(|t| t == topic) {
+            return vec![];
+        }
+        let mut overlapped: Vec<String> = self.watches.iter()
+            .map(|(filter, _)| filter.clone())
+            .filter(|filter| !self.overlaps_subscription(filter) && utils::filters_overlap(filter, topic))
+            .collect();
+        overlapped.sort();
+        overlapped.dedup();
+        self.subscriptions.push(topic.into());
+        return overlapped;
+    }
+
+    // Timers are known by their id, so setting one again at another time moves it. Returns false
+    // if the timer was already set for that time, so it doesn't get scheduled again.
+    pub fn record_timer(&mut self, timer: &TimedEvent) -> bool {
+        match self.timers.iter_mut().find(|t| t.id == timer.id) {
+            Some(known) if known == timer => return false,
+            Some(known) => *known = timer.clone(),
+            None => self.timers.push(timer.clone()),
+        }
+        return true;
+    }
+
+    pub fn record_message(&mut self, topic: &str, payload: &str) {
+        self.messages_received += 1;
+        *self.received_by_filter.entry(self.metric_filter(topic)).or_insert(0) += 1;
+        self.last_messages.insert(topic.into(), LastMessage { payload: payload.into(), received: Local::now() });
+        let line = format!("[{}] {}\n", topic, payload);
+        self.watches.retain(|(filter, tx)| !utils::topic_matches(filter, topic) || tx.send(ReplResponse::Stdout(line.clone())).is_ok());
+    }
+
+    // Whether the program itself subscribed to topic, as opposed to only watching it.
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        return self.subscriptions.iter().any(|filter| utils::topic_matches(filter, topic));
+    }
+
+    // Watched filters that overlap the program's subscriptions aren't subscribed to again, since
+    // the broker could then deliver a message twice and the program would handle it twice. They
+    // only see what the program receives.
+    pub fn overlaps_subscription(&self, filter: &str) -> bool {
+        return self.subscriptions.iter().any(|s| utils::filters_overlap(s, filter));
+    }
+
+    pub fn is_watched(&self, filter: &str) -> bool {
+        return self.watches.iter().any(|(f, _)| f == filter);
+    }
+
+    pub fn add_watch(&mut self, filter: String, tx: mpsc::Sender<ReplResponse>) {
+        self.watches.push((filter, tx));
+    }
+
+    // Stops watching filter, or everything if none is given, and returns the filters that were
+    // watched.
+    pub fn remove_watches(&mut self, filter: Option<&str>) -> Vec<String> {
+        let mut removed = vec![];
+        self.watches.retain(|(f, _)| {
+            if filter.map_or(true, |filter| filter == f) {
+                removed.push(f.clone());
+                return false;
+            }
+            return true;
+        });
+        removed.sort();
+        removed.dedup();
+        return removed;
+    }
+
+    // The filters the broker has to be subscribed to: the program's subscriptions and whatever
+    // the REPL watches.
+    pub fn broker_filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = self.subscriptions.iter()
+            .chain(self.watches.iter().map(|(filter, _)| filter).filter(|filter| !self.overlaps_subscription(filter)))
+            .cloned()
+            .collect();
+        filters.sort();
+        filters.dedup();
+        return filters;
+    }
+
+    pub fn record_publish(&mut self, topic: &str) {
+        self.messages_sent += 1;
+        *self.published_by_filter.entry(self.metric_filter(topic)).or_insert(0) += 1;
+    }
+
+    pub fn last_message(&self, topic: &str) -> Option<&LastMessage> {
+        return self.last_messages.get(topic);
+    }
+
+    pub fn subscriptions_listing(&self) -> String {
+        if self.subscriptions.is_empty() {
+            return "No active subscriptions.\n".into();
+        }
+        return self.subscriptions.iter()
+            .map(|topic| format!("{}\n", topic))
+            .collect();
+    }
+
+    pub fn timers_listing(&self) -> String {
+        if self.timers.is_empty() {
+            return "No timers set.\n".into();
+        }
+        let mut timers: Vec<(DateTime<Local>, &TimedEvent)> = self.timers.iter()
+            .map(|timer| (timer.get_next_time(), timer))
+            .collect();
+        timers.sort_by_key(|(next, _)| *next);
+        return timers.iter()
+            .map(|(next, timer)| format!("{} (daily at {:02}:{:02}), next at {}\n", timer.id, timer.time.0, timer.time.1, next.format("%Y-%m-%d %H:%M")))
+            .collect();
+    }
+
+    pub fn last_message_listing(&self, topic: &str) -> String {
+        match self.last_message(topic) {
+            Some(message) => format!("{} at {}\n", message.payload, message.received.format("%Y-%m-%d %H:%M:%S")),
+            None => format!("No message received on '{}' yet.\n", topic),
+        }
+    }
+
+    pub fn stats_listing(&self) -> String {
+        let uptime = self.uptime().as_secs();
+        return format!(
+            "uptime:            {}h {}m {}s\nmessages received: {}\nmessages sent:     {}\nsubscriptions:     {}\ntimers:            {}\nhandler runs:      {}\nhandler errors:    {}\nhandler aborts:    {}\n",
+            uptime / 3600, uptime % 3600 / 60, uptime % 60,
+            self.messages_received,
+            self.messages_sent,
+            self.subscriptions.len(),
+            self.timers.len(),
+            self.handler_runs,
+            self.handler_errors,
+            self.handler_aborts);
+    }
+
+    pub fn metrics_listing(&self) -> String {
+        let mut exposition = Exposition::new();
+        exposition.gauge("heinzelmann_uptime_seconds", "Seconds since heinzelmann started.", self.uptime().as_secs());
+        exposition.counter_by_filter("heinzelmann_messages_received_total", "Messages received from the broker, by topic filter.", &self.received_by_filter);
+        exposition.counter_by_filter("heinzelmann_messages_published_total", "Messages published by the program, by topic filter.", &self.published_by_filter);
+        exposition.counter("heinzelmann_handler_runs_total", "Event and timer handler invocations.", self.handler_runs);
+        exposition.counter("heinzelmann_handler_errors_total", "Handler invocations that failed.", self.handler_errors);
+        exposition.counter("heinzelmann_handler_aborts_total", "Handler invocations aborted for running too long.", self.handler_aborts);
+        exposition.histogram("heinzelmann_handler_duration_seconds", "How long handler invocations took.", &self.handler_latency);
+        exposition.counter("heinzelmann_timer_firings_total", "Timers that fired.", self.timers_fired);
+        exposition.gauge("heinzelmann_handler_queue_depth", "Handler invocations waiting for the VM.", self.pending_handlers);
+        exposition.counter("heinzelmann_mqtt_reconnects_total", "Reconnections to the broker after the first connection.", self.connections.saturating_sub(1));
+        exposition.gauge("heinzelmann_subscriptions", "Active subscriptions.", self.subscriptions.len() as u64);
+        exposition.gauge("heinzelmann_nrepl_sessions", "Open nREPL and control socket sessions.", self.nrepl_sessions);
+        exposition.counter("heinzelmann_nrepl_sessions_created_total", "nREPL and control socket sessions created.", self.nrepl_sessions_created);
+        exposition.custom(&self.custom_metrics);
+        return exposition.finish();
+    }
+}
@@ -0,0 +1,53 @@
+/*
+* This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+* This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+* You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Tells systemd when heinzelmann is ready and that the VM is still alive, see sd_notify(3).
+// Without a NOTIFY_SOCKET, as when not running as a Type=notify service, nothing is sent.
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+use crate::status::SharedStatus;
+
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    // Paths starting with @ are in the abstract namespace.
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(state.as_bytes(), &addr?));
+    if let Err(e) = sent {
+        log::warn!("Unable to notify systemd at {}: {}", path, e);
+    }
+}
+
+// Sends READY=1 the first time both the program has run and the broker is connected.
+pub fn notify_ready(status: &SharedStatus) {
+    if status.lock().unwrap().mark_ready() {
+        log::info!("Ready.");
+        notify("READY=1\nSTATUS=Running");
+    }
+}
+
+// How often systemd expects a WATCHDOG=1, if the service has WatchdogSec set.
+pub fn watchdog_interval() -> Option<Duration> {
+    // The watchdog may be meant for another process, like a wrapper script.
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+    return env::var("WATCHDOG_USEC").ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros);
+}
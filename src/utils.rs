@@ -4,28 +4,45 @@
 * You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>. 
 */
 
-use std::sync::mpsc;
+use std::sync::{mpsc, Mutex};
 use crate::TimedEvent;
+use crate::status::SharedStatus;
+use crate::audit::SharedAudit;
 use rumqttc::{Client, QoS};
 use bytes::Bytes;
 use rand::{distributions::Alphanumeric, Rng};
-use chrono::Local;
+use chrono::{DateTime, Local};
 
-pub fn subscribe_closure(client: Client) -> impl Fn(String) -> () {
+pub fn subscribe_closure(client: Client, status: SharedStatus) -> impl Fn(String) -> () {
     return move |topic| {
         let mut client = client.clone();
+        let overlapped = status.lock().unwrap().record_subscription(&topic);
         client.subscribe(topic, QoS::AtMostOnce).unwrap();
+        // Watches the new subscription overlaps are served by it from now on.
+        for filter in overlapped {
+            let _ = client.unsubscribe(filter);
+        }
     };
 }
 
-pub fn send_closure(client: Client, retain: bool) -> impl Fn(String, String) -> () {
+pub fn send_closure(client: Client, retain: bool, status: SharedStatus, audit: SharedAudit) -> impl Fn(String, String) -> () {
     return move |topic, payload| {
         let mut client = client.clone();
+        status.lock().unwrap().record_publish(&topic);
+        audit.lock().unwrap().record_current("publish", &[("topic", &topic), ("payload", &payload), ("retain", &retain.to_string())]);
         let payload = Bytes::from(payload);
         client.publish(topic, QoS::AtLeastOnce, retain, payload).unwrap();
     };
 }
 
+// Stands in for send_closure in a dry run, logging what would have been published.
+pub fn dry_send_closure(retain: bool, audit: SharedAudit) -> impl Fn(String, String) -> () {
+    return move |topic, payload| {
+        log::info!("Dry run, not publishing{} on '{}': {}", if retain { " (retained)" } else { "" }, topic, payload);
+        audit.lock().unwrap().record_current("publish", &[("topic", &topic), ("payload", &payload), ("retain", &retain.to_string()), ("dry_run", "true")]);
+    };
+}
+
 pub fn set_timer_closure(tx: mpsc::Sender<TimedEvent>) -> impl Fn(String, String) -> () {
     return move |time_str, id| {
         tx.send(TimedEvent::register(time_str, id)).unwrap();
@@ -41,8 +58,19 @@ pub fn random_string(length: usize) -> String {
     return s.to_lowercase();
 }
 
+// Replays run on a simulated clock, which current-timestamp follows.
+static SIMULATED_TIME: Mutex<Option<DateTime<Local>>> = Mutex::new(None);
+
+pub fn set_simulated_time(time: DateTime<Local>) {
+    *SIMULATED_TIME.lock().unwrap() = Some(time);
+}
+
+pub fn now() -> DateTime<Local> {
+    return SIMULATED_TIME.lock().unwrap().unwrap_or_else(Local::now);
+}
+
 pub fn current_timestamp() -> i64 {
-    return Local::now().timestamp();
+    return now().timestamp();
 }
 
 pub fn get_md5(inputs: Vec<String>) -> String {
@@ -54,3 +82,125 @@ pub fn get_md5(inputs: Vec<String>) -> String {
     let hexdigest = hex::encode(digest);
     return hexdigest;
 }
+
+// Converts a byte offset into code into a line and column, both starting at 1.
+pub fn line_and_column(code: &str, offset: usize) -> Option<(usize, usize)> {
+    let before = code.get(..offset)?;
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    return Some((line, column));
+}
+
+// Generates a random (version 4) UUID.
+pub fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    return format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]);
+}
+
+// Whether topic matches an MQTT topic filter, which may contain + and # wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// Whether some topic matches both filters, in which case the broker may deliver it once for each.
+pub fn filters_overlap(a: &str, b: &str) -> bool {
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => continue,
+            (Some(x), Some(y)) if x == y => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// The QoS level for a number from 0 to 2, where anything higher counts as 2.
+pub fn qos(level: u8) -> QoS {
+    match level {
+        0 => return QoS::AtMostOnce,
+        1 => return QoS::AtLeastOnce,
+        _ => return QoS::ExactlyOnce,
+    }
+}
+
+// Quotes s as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_without_wildcards_match_only_their_topic() {
+        assert!(topic_matches("home/kitchen/light", "home/kitchen/light"));
+        assert!(!topic_matches("home/kitchen/light", "home/kitchen"));
+        assert!(!topic_matches("home/kitchen", "home/kitchen/light"));
+        assert!(!topic_matches("home/kitchen/light", "home/hall/light"));
+    }
+
+    #[test]
+    fn plus_matches_exactly_one_level() {
+        assert!(topic_matches("home/+/light", "home/kitchen/light"));
+        assert!(topic_matches("home/+/light", "home//light"));
+        assert!(!topic_matches("home/+/light", "home/kitchen/ceiling/light"));
+        assert!(!topic_matches("home/+", "home"));
+    }
+
+    #[test]
+    fn hash_matches_the_rest_including_the_parent() {
+        assert!(topic_matches("#", "home/kitchen/light"));
+        assert!(topic_matches("home/#", "home/kitchen/light"));
+        assert!(topic_matches("home/#", "home"));
+        assert!(!topic_matches("home/#", "garden/light"));
+    }
+
+    #[test]
+    fn overlapping_filters() {
+        assert!(filters_overlap("a/#", "a/b"));
+        assert!(filters_overlap("a/b", "a/#"));
+        assert!(filters_overlap("a/+", "+/b"));
+        assert!(filters_overlap("a/#", "a"));
+        assert!(filters_overlap("#", "anything/at/all"));
+        assert!(filters_overlap("a/b", "a/b"));
+    }
+
+    #[test]
+    fn disjoint_filters() {
+        assert!(!filters_overlap("a/b", "a/c"));
+        assert!(!filters_overlap("a/+", "a"));
+        assert!(!filters_overlap("a/+", "a/b/c"));
+        assert!(!filters_overlap("a/#", "b/#"));
+    }
+}